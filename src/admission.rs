@@ -0,0 +1,359 @@
+//! Admission control of the completion requests.
+//!
+//! At most `max_in_flight` requests of a model are sent to Triton at the same time, the others
+//! wait in a bounded queue of the model. Waiting requests are admitted by priority of their API
+//! key, then in order of arrival. Requests are rejected when the queue is full, or when they
+//! waited too long.
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use axum::Extension;
+use tokio::sync::oneshot;
+
+use crate::auth::Identity;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::metrics::metrics;
+
+struct Waiter {
+    priority: i32,
+    /// Order of arrival, to admit the requests of the same priority first come, first served.
+    seq: u64,
+    admit: oneshot::Sender<()>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    /// The waiter admitted next is the greatest one.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct ModelQueue {
+    in_flight: usize,
+    waiting: BinaryHeap<Waiter>,
+}
+
+#[derive(Clone)]
+pub struct Admission {
+    /// Default maximum of requests in flight per model, unlimited when not set.
+    max_in_flight: Option<usize>,
+    /// Maximum of requests in flight of the models that set their own.
+    models_max_in_flight: Arc<HashMap<String, usize>>,
+    max_queue_size: usize,
+    queue_timeout: Duration,
+    queues: Arc<Mutex<HashMap<String, ModelQueue>>>,
+    next_seq: Arc<Mutex<u64>>,
+}
+
+impl Admission {
+    pub fn new(config: &Config) -> Self {
+        let models_max_in_flight = config
+            .models
+            .iter()
+            .filter_map(|(name, model)| Some((name.clone(), model.max_in_flight?)))
+            .collect();
+        Self {
+            max_in_flight: config.max_in_flight,
+            models_max_in_flight: Arc::new(models_max_in_flight),
+            max_queue_size: config.max_queue_size,
+            queue_timeout: Duration::from_secs(config.queue_timeout_secs),
+            queues: Default::default(),
+            next_seq: Default::default(),
+        }
+    }
+
+    /// Wait for the turn of a request in the queue of `label`, the name of the model in the
+    /// metrics. A request takes a single turn, whatever the number of inferences it makes.
+    pub(crate) async fn admit(
+        &self,
+        identity: &Option<Extension<Identity>>,
+        label: &str,
+    ) -> Result<Permit, AppError> {
+        let priority = identity
+            .as_ref()
+            .map_or(0, |Extension(identity)| identity.priority);
+        self.acquire(label, priority).await
+    }
+
+    async fn acquire(&self, model: &str, priority: i32) -> Result<Permit, AppError> {
+        let Some(max_in_flight) = self
+            .models_max_in_flight
+            .get(model)
+            .copied()
+            .or(self.max_in_flight)
+        else {
+            return Ok(Permit { admitted: None });
+        };
+        // Only created once admitted, since it lets the next request in when dropped.
+        let permit = || Permit {
+            admitted: Some((self.clone(), model.to_string())),
+        };
+
+        let (seq, admitted) = {
+            let mut queues = self.queues.lock().unwrap();
+            let queue = queues.entry(model.to_string()).or_default();
+            if queue.in_flight < max_in_flight && queue.waiting.is_empty() {
+                queue.in_flight += 1;
+                return Ok(permit());
+            }
+            if queue.waiting.len() >= self.max_queue_size {
+                tracing::warn!("the queue of model {} is full", model);
+                return Err(AppError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    format!(
+                        "The server is overloaded, too many requests for model '{}' are waiting.",
+                        model
+                    ),
+                )
+                .with_code("queue_full"));
+            }
+
+            let seq = {
+                let mut next_seq = self.next_seq.lock().unwrap();
+                *next_seq += 1;
+                *next_seq
+            };
+            let (admit, admitted) = oneshot::channel();
+            queue.waiting.push(Waiter {
+                priority,
+                seq,
+                admit,
+            });
+            metrics().queued(model, 1);
+            (seq, admitted)
+        };
+
+        // Leaves the queue when the request is dropped while waiting, e.g. on client disconnect.
+        let mut entry = QueueEntry {
+            admission: self,
+            model,
+            seq,
+            admitted,
+            waiting: true,
+        };
+        match tokio::time::timeout(self.queue_timeout, &mut entry.admitted).await {
+            Ok(Ok(())) => {
+                entry.waiting = false;
+                Ok(permit())
+            }
+            _ => {
+                drop(entry);
+                tracing::warn!("request for model {} timed out in the queue", model);
+                Err(AppError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!(
+                        "The server is overloaded, the request for model '{}' waited more than {:?}.",
+                        model, self.queue_timeout
+                    ),
+                )
+                .with_code("queue_timeout"))
+            }
+        }
+    }
+
+    /// Hand the slot of a completed request over to the next waiting request.
+    fn release(&self, model: &str) {
+        let mut queues = self.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(model) else {
+            return;
+        };
+        while let Some(waiter) = queue.waiting.pop() {
+            metrics().queued(model, -1);
+            if waiter.admit.send(()).is_ok() {
+                return;
+            }
+        }
+        queue.in_flight -= 1;
+    }
+}
+
+struct QueueEntry<'a> {
+    admission: &'a Admission,
+    model: &'a str,
+    seq: u64,
+    /// Kept until the entry is dropped, so that `release` can't hand a slot over to a request
+    /// that gave up without it being passed on.
+    admitted: oneshot::Receiver<()>,
+    waiting: bool,
+}
+
+impl Drop for QueueEntry<'_> {
+    fn drop(&mut self) {
+        if !self.waiting {
+            return;
+        }
+        let mut queues = self.admission.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(self.model) else {
+            return;
+        };
+        let waiting = queue.waiting.len();
+        queue.waiting.retain(|waiter| waiter.seq != self.seq);
+        if queue.waiting.len() < waiting {
+            metrics().queued(self.model, -1);
+            return;
+        }
+        drop(queues);
+        // The request was admitted right as it gave up, pass its slot on.
+        if self.admitted.try_recv().is_ok() {
+            self.admission.release(self.model);
+        }
+    }
+}
+
+/// The turn of a request, which lets the next one in once dropped.
+pub(crate) struct Permit {
+    admitted: Option<(Admission, String)>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some((admission, model)) = &self.admitted {
+            admission.release(model);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::response::IntoResponse;
+    use clap::Parser;
+
+    use super::*;
+
+    fn admission(max_in_flight: usize, max_queue_size: usize) -> Admission {
+        let mut config = Config::parse_from(["openai_trtllm"]);
+        config.max_in_flight = Some(max_in_flight);
+        config.max_queue_size = max_queue_size;
+        config.queue_timeout_secs = 1;
+        Admission::new(&config)
+    }
+
+    #[tokio::test]
+    pub async fn test_queue() {
+        let admission = admission(1, 1);
+
+        let first = admission.acquire("model", 0).await.unwrap();
+        let waiting = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.acquire("model", 0).await.map(|_| ()) }
+        });
+        tokio::task::yield_now().await;
+        let error = admission.acquire("model", 0).await.err().unwrap();
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            error.into_response().status()
+        );
+        // Other models have their own queue.
+        assert!(admission.acquire("other", 0).await.is_ok());
+
+        drop(first);
+        assert!(waiting.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    pub async fn test_priority() {
+        let admission = admission(1, 10);
+
+        let first = admission.acquire("model", 0).await.unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for priority in [0, 1, 0] {
+            let admission = admission.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let permit = admission.acquire("model", priority).await.unwrap();
+                tx.send(priority).unwrap();
+                drop(permit);
+            });
+            tokio::task::yield_now().await;
+        }
+
+        drop(first);
+        let mut order = Vec::new();
+        for _ in 0..3 {
+            order.push(rx.recv().await.unwrap());
+        }
+        assert_eq!(vec![1, 0, 0], order);
+    }
+
+    #[tokio::test]
+    pub async fn test_released_while_giving_up() {
+        let admission = admission(1, 10);
+
+        let first = admission.acquire("model", 0).await.unwrap();
+        let mut waiting = Box::pin(admission.acquire("model", 0));
+        // Poll the request once, so that it waits in the queue.
+        tokio::select! {
+            biased;
+            _ = &mut waiting => unreachable!(),
+            _ = async {} => {}
+        }
+        // The slot is handed over to the waiting request, which gives up before seeing it.
+        drop(first);
+        drop(waiting);
+
+        {
+            let queues = admission.queues.lock().unwrap();
+            assert_eq!(0, queues["model"].in_flight);
+            assert!(queues["model"].waiting.is_empty());
+        }
+        let _second = admission.acquire("model", 0).await.unwrap();
+        assert_eq!(1, admission.queues.lock().unwrap()["model"].in_flight);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    pub async fn test_timeout_while_released() {
+        let mut admission = admission(1, 10);
+        admission.queue_timeout = Duration::from_micros(50);
+
+        for _ in 0..200 {
+            let first = admission.acquire("model", 0).await.unwrap();
+            let waiting = tokio::spawn({
+                let admission = admission.clone();
+                async move { admission.acquire("model", 0).await.map(|_| ()) }
+            });
+            tokio::time::sleep(Duration::from_micros(50)).await;
+            drop(first);
+            // Admitted or timed out, the request doesn't hold a slot anymore.
+            let _ = waiting.await.unwrap();
+            let queues = admission.queues.lock().unwrap();
+            assert_eq!(0, queues["model"].in_flight);
+            assert!(queues["model"].waiting.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_queue_timeout() {
+        let admission = admission(1, 1);
+
+        let _first = admission.acquire("model", 0).await.unwrap();
+        let error = admission.acquire("model", 0).await.err().unwrap();
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            error.into_response().status()
+        );
+        // The request that timed out left the queue.
+        let queues = admission.queues.lock().unwrap();
+        assert!(queues["model"].waiting.is_empty());
+    }
+}
@@ -0,0 +1,306 @@
+//! Authentication of the clients with API keys.
+//!
+//! Keys come from `--api-key` and from a key file, which is reloaded whenever it changes so that
+//! keys can be added, rotated and revoked without restarting the gateway:
+//!
+//! ```toml
+//! [[keys]]
+//! name = "team-a"
+//! key = "sk-..."
+//! # Every model when empty.
+//! models = ["llama3-70b"]
+//! # Unix timestamp (in seconds) from which the key is rejected.
+//! expires_at = 1767225600
+//! # Overrides of the configured rate limits, see [`crate::rate_limit`].
+//! requests_per_minute = 60
+//! tokens_per_minute = 100000
+//! concurrent_requests = 4
+//! # Requests of keys with a higher priority wait less when models are busy, 0 by default.
+//! priority = 10
+//! ```
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+use figment::providers::{Format, Toml};
+use figment::Figment;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tracing::Instrument;
+
+use crate::error::AppError;
+use crate::rate_limit::Limits;
+
+/// Delay between two checks of the key file for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct ApiKey {
+    name: String,
+    key: String,
+    #[serde(default)]
+    models: Vec<String>,
+    expires_at: Option<u64>,
+    requests_per_minute: Option<u64>,
+    tokens_per_minute: Option<u64>,
+    concurrent_requests: Option<usize>,
+    /// Requests of keys with a higher priority leave the queues of the models first.
+    #[serde(default)]
+    priority: i32,
+}
+
+impl ApiKey {
+    fn limits(&self) -> Limits {
+        Limits {
+            requests_per_minute: self.requests_per_minute,
+            tokens_per_minute: self.tokens_per_minute,
+            concurrent_requests: self.concurrent_requests,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFile {
+    #[serde(default)]
+    keys: Vec<ApiKey>,
+}
+
+/// The key a request was authenticated with, available to the handlers as an extension.
+#[derive(Clone, Debug)]
+pub(crate) struct Identity {
+    pub name: String,
+    /// Models the key may use, every model when empty.
+    models: Vec<String>,
+    /// Limits of the key, the configured ones apply to those it does not set.
+    pub limits: Limits,
+    pub priority: i32,
+}
+
+impl Identity {
+    pub fn allows(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|allowed| allowed == model)
+    }
+}
+
+#[derive(Clone)]
+pub struct KeyStore {
+    api_key: Option<String>,
+    file: Option<String>,
+    keys: Arc<RwLock<Vec<ApiKey>>>,
+}
+
+impl KeyStore {
+    /// Load the keys, authentication is disabled when neither `api_key` nor `file` is given.
+    pub fn new(api_key: &Option<String>, file: &Option<String>) -> anyhow::Result<Self> {
+        let store = Self {
+            api_key: api_key.clone(),
+            file: file.clone(),
+            keys: Default::default(),
+        };
+        *store.keys.write().unwrap() = store.load()?;
+        Ok(store)
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.api_key.is_some() || self.file.is_some()
+    }
+
+    fn load(&self) -> anyhow::Result<Vec<ApiKey>> {
+        let mut keys = Vec::new();
+        if let Some(key) = &self.api_key {
+            keys.push(ApiKey {
+                name: "default".to_string(),
+                key: key.clone(),
+                models: Vec::new(),
+                expires_at: None,
+                requests_per_minute: None,
+                tokens_per_minute: None,
+                concurrent_requests: None,
+                priority: 0,
+            });
+        }
+        if let Some(file) = &self.file {
+            let contents = std::fs::read_to_string(file)
+                .with_context(|| format!("failed to read api key file {}", file))?;
+            let key_file: KeyFile = Figment::from(Toml::string(&contents))
+                .extract()
+                .with_context(|| format!("invalid api key file {}", file))?;
+            for key in &key_file.keys {
+                key.limits().validate().with_context(|| {
+                    format!("invalid limits of api key {} in {}", key.name, file)
+                })?;
+            }
+            keys.extend(key_file.keys);
+        }
+        Ok(keys)
+    }
+
+    /// Reload the key file in the background whenever it is modified. Invalid changes are
+    /// ignored, the previous keys stay in use.
+    pub fn watch(&self) {
+        let Some(file) = self.file.clone() else {
+            return;
+        };
+        let store = self.clone();
+        tokio::spawn(async move {
+            let modified = || std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+            let mut last_modified = modified();
+            loop {
+                tokio::time::sleep(RELOAD_INTERVAL).await;
+                let modified = modified();
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                match store.load() {
+                    Ok(keys) => {
+                        tracing::info!("reloaded {} api keys from {}", keys.len(), file);
+                        *store.keys.write().unwrap() = keys;
+                    }
+                    Err(e) => tracing::error!("failed to reload api keys: {:?}", e),
+                }
+            }
+        });
+    }
+
+    /// Find the key matching `token`, unless it expired.
+    fn authenticate(&self, token: &str) -> Option<Identity> {
+        let keys = self.keys.read().unwrap();
+        // Every key is compared, in constant time, so that the time taken tells nothing about
+        // the keys.
+        let mut found = None;
+        for key in keys.iter() {
+            if bool::from(key.key.as_bytes().ct_eq(token.as_bytes())) {
+                found = Some(key);
+            }
+        }
+        let key = found?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+        if key.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            tracing::info!("rejected expired api key {}", key.name);
+            return None;
+        }
+        Some(Identity {
+            name: key.name.clone(),
+            models: key.models.clone(),
+            limits: key.limits(),
+            priority: key.priority,
+        })
+    }
+}
+
+pub(crate) async fn auth_middleware(
+    State(keys): State<KeyStore>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Result<Response, AppError> {
+    if !keys.is_enabled() {
+        return Ok(next.run(req).await);
+    }
+
+    let identity = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| keys.authenticate(token));
+    let Some(identity) = identity else {
+        return Err(
+            AppError::new(StatusCode::UNAUTHORIZED, "Incorrect API key provided.")
+                .with_code("invalid_api_key"),
+        );
+    };
+
+    let span = tracing::info_span!("authenticated", api_key = %identity.name);
+    req.extensions_mut().insert(identity);
+    Ok(next.run(req).instrument(span).await)
+}
+
+/// Reject the request when the key it was authenticated with may not use `model`.
+pub(crate) fn authorize_model(
+    identity: &Option<Extension<Identity>>,
+    model: &str,
+) -> Result<(), AppError> {
+    match identity {
+        Some(Extension(identity)) if !identity.allows(model) => Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            format!(
+                "The model '{}' does not exist or you do not have access to it.",
+                model
+            ),
+        )
+        .with_code("model_not_found")),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store(keys: &str) -> KeyStore {
+        let file = std::env::temp_dir().join(format!("api_keys_{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&file, keys).unwrap();
+        let store = KeyStore::new(
+            &Some("shared".to_string()),
+            &Some(file.to_string_lossy().to_string()),
+        );
+        std::fs::remove_file(&file).unwrap();
+        store.unwrap()
+    }
+
+    #[test]
+    pub fn test_authenticate() {
+        let store = store(
+            r#"
+            [[keys]]
+            name = "team-a"
+            key = "sk-a"
+            models = ["llama3-70b"]
+
+            [[keys]]
+            name = "expired"
+            key = "sk-expired"
+            expires_at = 1
+            "#,
+        );
+
+        let identity = store.authenticate("sk-a").unwrap();
+        assert_eq!("team-a", identity.name);
+        assert!(identity.allows("llama3-70b"));
+        assert!(!identity.allows("llama3-8b"));
+
+        let identity = store.authenticate("shared").unwrap();
+        assert_eq!("default", identity.name);
+        assert!(identity.allows("llama3-8b"));
+
+        assert!(store.authenticate("sk-expired").is_none());
+        assert!(store.authenticate("sk-").is_none());
+    }
+
+    #[test]
+    pub fn test_invalid_key_file() {
+        for keys in [
+            "[[keys]]\nname = \"team-a\"\n",
+            "[[keys]]\nname = \"team-a\"\nkey = \"sk-a\"\nrequests_per_minute = 0\n",
+        ] {
+            let file = std::env::temp_dir().join(format!("api_keys_{}.toml", uuid::Uuid::new_v4()));
+            std::fs::write(&file, keys).unwrap();
+            let store = KeyStore::new(&None, &Some(file.to_string_lossy().to_string()));
+            std::fs::remove_file(&file).unwrap();
+            assert!(store.is_err());
+        }
+    }
+}
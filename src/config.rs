@@ -1,8 +1,21 @@
-use clap::Parser;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use figment::providers::{Env, Format, Serialized, Toml};
+use figment::Figment;
 use serde::{Deserialize, Serialize};
 
+use crate::utils::string_or_seq_string;
+
 #[derive(Parser, Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// Configuration file (TOML), e.g. to declare the models served by the gateway
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_file: Option<String>,
+
     /// Host to bind to
     #[arg(long, short = 'H', default_value_t = String::from("0.0.0.0"))]
     pub host: String,
@@ -11,15 +24,64 @@ pub struct Config {
     #[arg(long, short, default_value_t = 3000)]
     pub port: usize,
 
-    /// Triton gRPC endpoint
-    #[arg(long, short, default_value_t = String::from("http://localhost:8001"))]
-    pub triton_endpoint: String,
+    /// Triton gRPC endpoint, several replicas can be given separated by commas
+    #[arg(
+        long,
+        short,
+        value_delimiter = ',',
+        default_value = "http://localhost:8001"
+    )]
+    #[serde(deserialize_with = "string_or_seq_string")]
+    pub triton_endpoint: Vec<String>,
+
+    /// How requests are spread over the replicas of the Triton endpoint
+    #[arg(long, value_enum, default_value_t = LoadBalancing::LeastOutstanding)]
+    pub load_balancing: LoadBalancing,
+
+    /// Backend of the Triton models, which sets the inputs of the completion requests
+    #[arg(long, value_enum, default_value_t = Backend::Vllm)]
+    pub backend: Backend,
+
+    /// Seconds during which a replica answering `Unavailable` receives no request
+    #[arg(long, default_value_t = 10)]
+    pub ejection_secs: u64,
+
+    /// Seconds after which a completion request is aborted
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_timeout_secs: Option<u64>,
+
+    /// Seconds to wait for the first generated token before aborting a completion request
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_token_timeout_secs: Option<u64>,
+
+    /// Requests of a model sent to Triton at the same time, the others wait in the queue of the model
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_in_flight: Option<usize>,
+
+    /// Requests that may wait in the queue of a model, the others are rejected
+    #[arg(long, default_value_t = 100)]
+    pub max_queue_size: usize,
+
+    /// Seconds a request may wait in the queue of a model
+    #[arg(long, default_value_t = 30)]
+    pub queue_timeout_secs: u64,
+
+    /// Choices a completion request may ask for, `n` times the number of prompts
+    #[arg(long, default_value_t = 128)]
+    pub max_n: usize,
 
     /// Endpoint of OpenTelemetry collector
     #[arg(long, short)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub otlp_endpoint: Option<String>,
 
+    /// Also export metrics to the OpenTelemetry collector, they are always served on /metrics
+    #[arg(long)]
+    pub otlp_metrics: bool,
+
     /// Template for converting OpenAI message history to prompt
     #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -30,8 +92,230 @@ pub struct Config {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub history_template_file: Option<String>,
 
+    /// Template of the fill-in-the-middle prompt of completions with a suffix, e.g.
+    /// `<fim_prefix>{{ prompt }}<fim_suffix>{{ suffix }}<fim_middle>`
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fim_template: Option<String>,
+
+    /// Tokenizer file (tokenizer.json) used to count tokens when the backend does not report them
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokenizer_file: Option<String>,
+
+    /// Request the additional outputs of the vLLM backend (token counts, ...), needs Triton 24.12+
+    #[arg(long)]
+    pub vllm_additional_outputs: bool,
+
+    /// Tag marking the beginning of a tool call in the model output
+    #[arg(long, default_value_t = String::from("<tool_call>"))]
+    pub tool_call_start: String,
+
+    /// Tag marking the end of a tool call in the model output
+    #[arg(long, default_value_t = String::from("</tool_call>"))]
+    pub tool_call_end: String,
+
+    /// Alias of a Triton model, as ALIAS=MODEL, can be repeated
+    #[arg(long, value_name = "ALIAS=MODEL")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub model_alias: Vec<String>,
+
     /// Api Key to access the server
     #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub api_key: Option<String>,
+
+    /// File of API keys, reloaded when it changes, see the README for its format
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_keys_file: Option<String>,
+
+    /// Route groups served without authentication
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [RouteGroup::Health, RouteGroup::Metrics]
+    )]
+    pub public_routes: Vec<RouteGroup>,
+
+    /// Requests per minute of every API key, or of every IP address without authentication
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u64>,
+
+    /// Prompt and generated tokens per minute of every API key, or IP address
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_per_minute: Option<u64>,
+
+    /// Concurrent completion requests of every API key, or IP address
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrent_requests: Option<usize>,
+
+    /// Models served by the gateway, keyed by the name clients use, only set by the config file
+    #[arg(skip)]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub models: BTreeMap<String, ModelConfig>,
+}
+
+impl Config {
+    /// Load the configuration from the defaults, overridden by `--config-file`, then by the
+    /// `OPENAI_TRTLLM_` environment variables, then by the flags given on the command line.
+    pub fn load<I, T>(args: I) -> anyhow::Result<Config>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Config::command().get_matches_from(args);
+        let args = Config::from_arg_matches(&matches)?;
+
+        let mut figment = Figment::from(Serialized::defaults(&args));
+        if let Some(config_file) = &args.config_file {
+            anyhow::ensure!(
+                std::path::Path::new(config_file).is_file(),
+                "config file {} does not exist",
+                config_file
+            );
+            figment = figment.merge(Toml::file(config_file));
+        }
+        // The defaults of the other flags must not override the configuration file.
+        let serde_json::Value::Object(mut flags) = serde_json::to_value(&args)? else {
+            unreachable!("the configuration is a struct");
+        };
+        flags.retain(|name, _| {
+            matches.ids().any(|id| id.as_str() == name)
+                && matches.value_source(name) == Some(ValueSource::CommandLine)
+        });
+
+        Ok(figment
+            .merge(Env::prefixed("OPENAI_TRTLLM_"))
+            .merge(Serialized::defaults(flags))
+            .extract()?)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LoadBalancing {
+    /// Send requests to every replica in turn
+    RoundRobin,
+    /// Send requests to the replica with the fewest requests in flight
+    LeastOutstanding,
+}
+
+/// Backend of a Triton model, the inputs of the requests of `/v1/completions` depend on it.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// vLLM backend, sampling parameters given as JSON
+    Vllm,
+    /// TensorRT-LLM backend, sampling parameters given as tensors
+    TensorrtLlm,
+}
+
+/// Routes sharing the same authentication policy.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RouteGroup {
+    /// /health_check, /health/live and /health/ready
+    Health,
+    /// /metrics
+    Metrics,
+    /// /v1/models and /v1/models/{model}
+    Models,
+}
+
+/// How to serve a model, every field defaults to the corresponding global option.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    /// Name of the Triton model, defaults to the name of the model
+    pub triton_model: Option<String>,
+    /// Version of the Triton model, defaults to the one chosen by the version policy of Triton
+    pub triton_model_version: Option<String>,
+    /// Backend of the Triton model
+    pub backend: Option<Backend>,
+    /// Triton gRPC endpoint serving the model, or list of its replicas
+    #[serde(default, deserialize_with = "string_or_seq_string")]
+    pub triton_endpoint: Vec<String>,
+    /// Template for converting OpenAI message history to prompt
+    pub history_template: Option<String>,
+    /// File containing the history template string
+    pub history_template_file: Option<String>,
+    /// Template of the fill-in-the-middle prompt of completions with a suffix
+    pub fim_template: Option<String>,
+    /// Whether the Triton model takes the prompts given as token ids, as an `input_ids` tensor
+    #[serde(default)]
+    pub input_ids: bool,
+    /// Default sampling parameters, overridden by the ones of the request
+    #[serde(default)]
+    pub sampling_parameters: serde_json::Map<String, serde_json::Value>,
+    /// Requests of the model sent to Triton at the same time
+    pub max_in_flight: Option<usize>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_models_from_config_file() {
+        let config: Config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [models.llama3-70b]
+                triton_model = "llama3_70b"
+                triton_endpoint = ["http://triton-a:8001", "http://triton-b:8001"]
+                history_template_file = "templates/history_template_llama3.liquid"
+
+                [models.llama3-70b.sampling_parameters]
+                temperature = 0.6
+
+                [models."qwen2.5-7b"]
+                backend = "tensorrt-llm"
+                "#,
+            ))
+            .merge(Serialized::defaults(Config::parse_from(["openai_trtllm"])))
+            .extract()
+            .unwrap();
+
+        assert_eq!(2, config.models.len());
+        let llama = &config.models["llama3-70b"];
+        assert_eq!(Some("llama3_70b"), llama.triton_model.as_deref());
+        assert_eq!(
+            vec!["http://triton-a:8001", "http://triton-b:8001"],
+            llama.triton_endpoint
+        );
+        assert_eq!(vec!["http://localhost:8001"], config.triton_endpoint);
+        assert_eq!(0.6, llama.sampling_parameters["temperature"]);
+        assert!(config.models["qwen2.5-7b"].triton_model.is_none());
+        assert!(config.models["qwen2.5-7b"].triton_endpoint.is_empty());
+        assert_eq!(Backend::Vllm, config.backend);
+        assert!(llama.backend.is_none());
+        assert_eq!(
+            Some(Backend::TensorrtLlm),
+            config.models["qwen2.5-7b"].backend
+        );
+    }
+
+    #[test]
+    pub fn test_load_config_file() {
+        let file = std::env::temp_dir().join(format!("config_{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&file, "port = 4000\nmax_queue_size = 10\n").unwrap();
+        let file = file.to_string_lossy().to_string();
+
+        let config = Config::load(["openai_trtllm", "--config-file", &file]).unwrap();
+        assert_eq!(4000, config.port);
+        assert_eq!(10, config.max_queue_size);
+        assert_eq!("0.0.0.0", config.host);
+
+        // Flags given on the command line win.
+        let config =
+            Config::load(["openai_trtllm", "--config-file", &file, "--port", "5000"]).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(5000, config.port);
+        assert_eq!(10, config.max_queue_size);
+    }
 }
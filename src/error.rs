@@ -1,18 +1,127 @@
+//! Errors reported to clients in the format of the OpenAI API.
+//!
+//! https://platform.openai.com/docs/guides/error-codes
+use std::fmt;
+
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{FromRequest, Request};
+use axum::response::sse::Event;
 use axum::{
+    async_trait,
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
+use serde_json::json;
+use tonic::Code;
 
 #[derive(Debug)]
-pub struct AppError(anyhow::Error);
+pub struct AppError {
+    status: StatusCode,
+    message: String,
+    param: Option<String>,
+    code: Option<&'static str>,
+}
+
+impl AppError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            param: None,
+            code: None,
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    /// Map an error message returned by Triton within an inference response.
+    ///
+    /// Triton reports errors of a streaming inference as text only, so the status is guessed from
+    /// the messages of Triton and its vLLM backend.
+    pub fn from_triton_message(message: &str) -> Self {
+        let lowercase = message.to_lowercase();
+        let (status, code) = if lowercase.contains("unknown model")
+            || (lowercase.contains("model") && lowercase.contains("is not found"))
+        {
+            (StatusCode::NOT_FOUND, Some("model_not_found"))
+        } else if lowercase.contains("exceeds maximum queue size") {
+            (StatusCode::TOO_MANY_REQUESTS, Some("rate_limit_exceeded"))
+        } else if lowercase.contains("not ready") || lowercase.contains("unavailable") {
+            (StatusCode::SERVICE_UNAVAILABLE, None)
+        } else if lowercase.contains("unexpected inference input")
+            || lowercase.contains("invalid argument")
+            || lowercase.contains("too long")
+            || lowercase.contains("must be")
+        {
+            (StatusCode::BAD_REQUEST, None)
+        } else {
+            (StatusCode::INTERNAL_SERVER_ERROR, None)
+        };
+        Self {
+            status,
+            message: message.to_string(),
+            param: None,
+            code,
+        }
+    }
+
+    fn from_status(status: &tonic::Status) -> Self {
+        let (http_status, code) = match status.code() {
+            Code::InvalidArgument | Code::OutOfRange | Code::FailedPrecondition => {
+                (StatusCode::BAD_REQUEST, None)
+            }
+            Code::NotFound => (StatusCode::NOT_FOUND, Some("model_not_found")),
+            Code::Unauthenticated => (StatusCode::UNAUTHORIZED, None),
+            Code::PermissionDenied => (StatusCode::FORBIDDEN, None),
+            Code::ResourceExhausted => (StatusCode::TOO_MANY_REQUESTS, Some("rate_limit_exceeded")),
+            Code::Unavailable => (StatusCode::SERVICE_UNAVAILABLE, None),
+            Code::DeadlineExceeded => (StatusCode::GATEWAY_TIMEOUT, None),
+            _ => return Self::from_triton_message(status.message()),
+        };
+        Self {
+            status: http_status,
+            message: status.message().to_string(),
+            param: None,
+            code,
+        }
+    }
+
+    /// The `{"error": {...}}` envelope sent in the response body.
+    pub fn body(&self) -> serde_json::Value {
+        let kind = match self.status {
+            StatusCode::UNAUTHORIZED => "authentication_error",
+            StatusCode::FORBIDDEN => "permission_error",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+            status if status.is_client_error() => "invalid_request_error",
+            _ => "server_error",
+        };
+        json!({
+            "error": {
+                "message": self.message,
+                "type": kind,
+                "param": self.param,
+                "code": self.code,
+            }
+        })
+    }
+
+    /// The error as an `error` event, once a streaming response started.
+    // Corresponds to https://github.com/openai/openai-python/blob/17ac6779958b2b74999c634c4ea4c7b74906027a/src/openai/_streaming.py#L113
+    pub fn event(&self) -> Event {
+        Event::default()
+            .event("error")
+            .json_data(self.body())
+            .unwrap()
+    }
+}
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "An error occurred while trying to fulfill your request.",
-        )
-            .into_response()
+        (self.status, Json(self.body())).into_response()
     }
 }
 
@@ -21,6 +130,128 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        let err = err.into();
+        for cause in err.chain() {
+            if let Some(invalid) = cause.downcast_ref::<InvalidRequest>() {
+                return Self {
+                    status: StatusCode::BAD_REQUEST,
+                    message: invalid.message.clone(),
+                    param: invalid.param.clone(),
+                    code: None,
+                };
+            }
+            if let Some(rejection) = cause.downcast_ref::<JsonRejection>() {
+                return Self::new(rejection.status(), rejection.body_text());
+            }
+            if let Some(status) = cause.downcast_ref::<tonic::Status>() {
+                let error = Self::from_status(status);
+                if error.status.is_server_error() {
+                    tracing::error!("triton request failed: {:?}", err);
+                }
+                return error;
+            }
+        }
+        tracing::error!("failed to fulfill request: {:?}", err);
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "An error occurred while trying to fulfill your request.",
+        )
+    }
+}
+
+/// An error caused by the content of the client request, reported with status 400.
+#[derive(Debug)]
+pub(crate) struct InvalidRequest {
+    message: String,
+    param: Option<String>,
+}
+
+impl InvalidRequest {
+    /// An error about the request parameter `param`.
+    pub fn param(param: &str, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            param: Some(param.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for InvalidRequest {}
+
+/// Same as [`axum::Json`], but rejects malformed request bodies with an [`AppError`].
+pub(crate) struct AppJson<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for AppJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Ok(Self(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_status_mapping() {
+        let error = AppError::from(tonic::Status::invalid_argument("bad input"));
+        assert_eq!(StatusCode::BAD_REQUEST, error.status);
+        assert_eq!("invalid_request_error", error.body()["error"]["type"]);
+
+        let error = AppError::from(tonic::Status::unavailable("no backend"));
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, error.status);
+        assert_eq!("server_error", error.body()["error"]["type"]);
+
+        let error = AppError::from(
+            anyhow::Error::new(tonic::Status::resource_exhausted("busy")).context("failed"),
+        );
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, error.status);
+    }
+
+    #[test]
+    pub fn test_triton_message_mapping() {
+        let error =
+            AppError::from_triton_message("Request for unknown model: 'llama' is not found");
+        assert_eq!(StatusCode::NOT_FOUND, error.status);
+        assert_eq!("model_not_found", error.body()["error"]["code"]);
+
+        let error = AppError::from_triton_message("Exceeds maximum queue size");
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, error.status);
+
+        let error = AppError::from_triton_message("CUDA out of memory");
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, error.status);
+    }
+
+    #[test]
+    pub fn test_invalid_request() {
+        let error = AppError::from(anyhow::anyhow!(InvalidRequest::param(
+            "top_p",
+            "top_p must be in (0, 1], got 0"
+        )));
+        assert_eq!(StatusCode::BAD_REQUEST, error.status);
+        assert_eq!(
+            json!({
+                "error": {
+                    "message": "top_p must be in (0, 1], got 0",
+                    "type": "invalid_request_error",
+                    "param": "top_p",
+                    "code": null,
+                }
+            }),
+            error.body()
+        );
     }
 }
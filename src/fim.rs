@@ -0,0 +1,42 @@
+//! Fill-in-the-middle prompts, for code models completing the text between a prompt and a suffix.
+use std::sync::Arc;
+
+use liquid::{ParserBuilder, Template};
+
+/// Template rendering the `prompt` and the `suffix` of a completion request into the prompt of
+/// the model, e.g. `<fim_prefix>{{ prompt }}<fim_suffix>{{ suffix }}<fim_middle>`.
+#[derive(Clone)]
+pub struct FimTemplate {
+    template: Arc<Template>,
+}
+
+impl FimTemplate {
+    pub fn new(template: &str) -> anyhow::Result<Self> {
+        let template = ParserBuilder::with_stdlib().build()?.parse(template)?;
+        Ok(Self {
+            template: Arc::new(template),
+        })
+    }
+
+    pub fn render(&self, prompt: &str, suffix: &str) -> anyhow::Result<String> {
+        let context = liquid::object!({
+            "prompt": prompt,
+            "suffix": suffix,
+        });
+        Ok(self.template.render(&context)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_render() {
+        let template = FimTemplate::new("<PRE> {{ prompt }} <SUF>{{ suffix }} <MID>").unwrap();
+        assert_eq!(
+            "<PRE> def add(a, b): <SUF>    return c <MID>",
+            template.render("def add(a, b):", "    return c").unwrap()
+        );
+    }
+}
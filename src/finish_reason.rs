@@ -0,0 +1,60 @@
+//! Determine why the backend stopped generating.
+use crate::triton::response::string_output;
+use crate::triton::ModelInferResponse;
+
+pub(crate) const FINISH_REASON: &str = "finish_reason";
+
+/// Keeps track of the stop cause of a generation across streamed responses.
+#[derive(Default, Debug)]
+pub(crate) struct FinishReasonTracker {
+    reported: Option<String>,
+}
+
+impl FinishReasonTracker {
+    pub fn record(&mut self, response: &ModelInferResponse) -> anyhow::Result<()> {
+        // The vLLM backend reports "None" for as long as the generation is not finished.
+        if let Some(reason) = string_output(response, FINISH_REASON)? {
+            if !reason.is_empty() && reason != "None" {
+                self.reported = Some(reason);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the generation was cut off by the `max_tokens` budget.
+    ///
+    /// The finish reason reported by the backend is authoritative. Without it, the number of
+    /// generated tokens is compared against the budget.
+    pub fn is_length(&self, completion_tokens: Option<usize>, max_tokens: usize) -> bool {
+        match self.reported.as_deref() {
+            Some(reason) => reason == "length",
+            None => completion_tokens.is_some_and(|tokens| tokens >= max_tokens),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_reported_reason_wins() {
+        let tracker = FinishReasonTracker {
+            reported: Some("stop".into()),
+        };
+        assert!(!tracker.is_length(Some(16), 16));
+
+        let tracker = FinishReasonTracker {
+            reported: Some("length".into()),
+        };
+        assert!(tracker.is_length(None, 16));
+    }
+
+    #[test]
+    pub fn test_token_count_fallback() {
+        let tracker = FinishReasonTracker::default();
+        assert!(tracker.is_length(Some(16), 16));
+        assert!(!tracker.is_length(Some(15), 16));
+        assert!(!tracker.is_length(None, 16));
+    }
+}
@@ -1,4 +1,7 @@
-use crate::routes::chat::ChatCompletionMessageParams;
+use crate::routes::chat::{
+    ChatCompletionMessageParams, ChatCompletionMessageToolCall, ChatCompletionTool,
+    ChatCompletionToolChoice,
+};
 use anyhow::bail;
 use liquid::{ParserBuilder, Template};
 use serde::Serialize;
@@ -43,8 +46,35 @@ impl HistoryBuilder {
         &self,
         messages: &Vec<ChatCompletionMessageParams>,
     ) -> anyhow::Result<String> {
-        let items: Vec<_> = messages.iter().map(|x| HistoryItem::new(x)).collect();
-        let context = liquid::object!({"items": items});
+        self.build_history_with_tools(messages, None, None)
+    }
+
+    /// Render the history, exposing the tools the model may call to the template as `tools`
+    /// and the requested tool choice as `tool_choice`.
+    pub fn build_history_with_tools(
+        &self,
+        messages: &[ChatCompletionMessageParams],
+        tools: Option<&[ChatCompletionTool]>,
+        tool_choice: Option<&ChatCompletionToolChoice>,
+    ) -> anyhow::Result<String> {
+        let items: Vec<_> = messages.iter().map(HistoryItem::new).collect();
+        let tools = tools
+            .unwrap_or_default()
+            .iter()
+            .map(ToolItem::new)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let tool_choice = match tool_choice {
+            None => None,
+            Some(ChatCompletionToolChoice::Mode(mode)) => Some(serde_json::to_value(mode)?),
+            Some(ChatCompletionToolChoice::Named(named)) => {
+                Some(named.function.name.clone().into())
+            }
+        };
+        let context = liquid::object!({
+            "items": items,
+            "tools": tools,
+            "tool_choice": tool_choice,
+        });
         Ok(self.history_template.render(&context)?)
     }
 }
@@ -61,10 +91,14 @@ struct HistoryItem {
     identity: String,
     content: String,
     name: Option<String>,
+    tool_calls: Vec<ToolCallItem>,
+    tool_call_id: Option<String>,
 }
 
 impl HistoryItem {
     pub fn new(message: &ChatCompletionMessageParams) -> Self {
+        let mut tool_calls = Vec::new();
+        let mut tool_call_id = None;
         let (identity, content, name) = match message {
             ChatCompletionMessageParams::System { content, name } => {
                 ("System".into(), content.clone(), name.clone())
@@ -72,10 +106,22 @@ impl HistoryItem {
             ChatCompletionMessageParams::User { content, name } => {
                 ("User".into(), content.clone(), name.clone())
             }
-            ChatCompletionMessageParams::Assistant { content } => {
-                ("Assistant".into(), content.clone(), None)
+            ChatCompletionMessageParams::Assistant {
+                content,
+                tool_calls: calls,
+            } => {
+                tool_calls = calls.iter().flatten().map(ToolCallItem::new).collect();
+                (
+                    "Assistant".into(),
+                    content.clone().unwrap_or_default(),
+                    None,
+                )
             }
-            ChatCompletionMessageParams::Tool { content, .. } => {
+            ChatCompletionMessageParams::Tool {
+                content,
+                tool_call_id: id,
+            } => {
+                tool_call_id = Some(id.clone());
                 ("Tool".into(), content.clone(), None)
             }
         };
@@ -84,10 +130,49 @@ impl HistoryItem {
             identity,
             content,
             name,
+            tool_calls,
+            tool_call_id,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ToolCallItem {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+impl ToolCallItem {
+    fn new(tool_call: &ChatCompletionMessageToolCall) -> Self {
+        ToolCallItem {
+            id: tool_call.id.clone(),
+            name: tool_call.function.name.clone(),
+            arguments: tool_call.function.arguments.clone(),
         }
     }
 }
 
+#[derive(Serialize)]
+struct ToolItem {
+    name: String,
+    description: Option<String>,
+    parameters: Option<serde_json::Value>,
+    /// The whole tool definition serialized as JSON, as most chat templates embed it verbatim.
+    json: String,
+}
+
+impl ToolItem {
+    fn new(tool: &ChatCompletionTool) -> anyhow::Result<Self> {
+        Ok(ToolItem {
+            name: tool.function.name.clone(),
+            description: tool.function.description.clone(),
+            parameters: tool.function.parameters.clone(),
+            json: serde_json::to_string(tool)?,
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -109,7 +194,8 @@ mod test {
                 name: None,
             },
             ChatCompletionMessageParams::Assistant {
-                content: "test assistant 1".into(),
+                content: Some("test assistant 1".into()),
+                tool_calls: None,
             },
             ChatCompletionMessageParams::Tool {
                 content: "test tool 1".into(),
@@ -161,7 +247,8 @@ ASSISTANT:"
                 name: None,
             },
             ChatCompletionMessageParams::Assistant {
-                content: "test assistant 1".into(),
+                content: Some("test assistant 1".into()),
+                tool_calls: None,
             },
             ChatCompletionMessageParams::Tool {
                 content: "test tool 1".into(),
@@ -213,7 +300,8 @@ ASSISTANT:"
                 name: None,
             },
             ChatCompletionMessageParams::Assistant {
-                content: "test assistant 1".into(),
+                content: Some("test assistant 1".into()),
+                tool_calls: None,
             },
             ChatCompletionMessageParams::Tool {
                 content: "test tool 1".into(),
@@ -245,6 +333,71 @@ ASSISTANT:"
         assert_eq!(expected_result, result)
     }
 
+    #[test]
+    pub fn test_template_file_tools() {
+        let template = None;
+        let template_file = Some(format!(
+            "{}/templates/history_template_hermes_tools.liquid",
+            env!("CARGO_MANIFEST_DIR")
+        ));
+        let builder = HistoryBuilder::new(&template, &template_file)
+            .expect("tools template should build correctly");
+
+        let tools: Vec<ChatCompletionTool> = serde_json::from_str(
+            r#"[{"type": "function", "function": {"name": "get_weather", "parameters": {"type": "object"}}}]"#,
+        )
+        .expect("tools should deserialize");
+        let tool_calls = serde_json::from_str(
+            r#"[{"id": "call_1", "type": "function", "function": {"name": "get_weather", "arguments": "{\"city\": \"Paris\"}"}}]"#,
+        )
+        .expect("tool calls should deserialize");
+
+        let messages = vec![
+            ChatCompletionMessageParams::User {
+                content: "weather in Paris?".into(),
+                name: None,
+            },
+            ChatCompletionMessageParams::Assistant {
+                content: None,
+                tool_calls: Some(tool_calls),
+            },
+            ChatCompletionMessageParams::Tool {
+                content: "sunny".into(),
+                tool_call_id: "call_1".into(),
+            },
+        ];
+
+        let result = builder
+            .build_history_with_tools(&messages, Some(&tools), None)
+            .expect("history should build correctly");
+
+        let expected_result: String = r#"<|im_start|>system
+You may call one or more functions to assist with the user query. You are provided with function signatures within <tools></tools> XML tags:
+<tools>
+{"type":"function","function":{"name":"get_weather","parameters":{"type":"object"}}}
+</tools>
+For each function call, return a json object with function name and arguments within <tool_call></tool_call> XML tags:
+<tool_call>
+{"name": <function-name>, "arguments": <args-json-object>}
+</tool_call><|im_end|>
+<|im_start|>user
+weather in Paris?<|im_end|>
+<|im_start|>assistant
+
+<tool_call>
+{"name": "get_weather", "arguments": {"city": "Paris"}}
+</tool_call><|im_end|>
+<|im_start|>user
+<tool_response>
+sunny
+</tool_response><|im_end|>
+<|im_start|>assistant
+"#
+        .into();
+
+        assert_eq!(expected_result, result)
+    }
+
     #[test]
     pub fn test_validations() {
         let template = Some("abc".into());
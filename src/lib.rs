@@ -1,10 +1,24 @@
+mod admission;
+mod auth;
 pub mod config;
 mod error;
+mod fim;
+mod finish_reason;
 pub mod history;
+mod logprobs;
+mod metrics;
+mod rate_limit;
+pub mod registry;
+mod response_format;
 pub mod routes;
+mod sampling;
 pub mod startup;
 pub mod state;
+mod stop;
+mod stream_options;
 pub mod telemetry;
+pub mod tool_calls;
+pub mod usage;
 mod utils;
 
 mod triton;
@@ -0,0 +1,193 @@
+//! Log probabilities of the generated tokens.
+//!
+//! When `return_logprobs` is set, the vLLM backend returns a JSON encoded `logprobs` output: for
+//! every token generated since the previous response, the candidate token ids at its position
+//! mapped to their `logprob`, `rank` and `decoded_token`. The sampled token comes first, followed
+//! by the most likely tokens, as many as the `logprobs` sampling parameter asks for.
+use std::fmt;
+
+use anyhow::Context;
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crate::triton::request::{Builder, InferTensorData};
+use crate::triton::response::string_output;
+use crate::triton::ModelInferResponse;
+
+pub(crate) const LOGPROBS: &str = "logprobs";
+
+/// Log probability reported for tokens that can't be sampled, as OpenAI does.
+const MIN_LOGPROB: f32 = -9999.0;
+
+/// Ask the vLLM backend for the log probabilities of the generated tokens.
+pub(crate) fn request_logprobs(builder: Builder) -> Builder {
+    builder
+        .input("return_logprobs", [1], InferTensorData::Bool(vec![true]))
+        .output(LOGPROBS)
+}
+
+/// Log probability of a generated token, and of the most likely tokens at its position.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    /// The most likely tokens, the most likely first.
+    pub top_logprobs: Vec<(String, f32)>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Candidate {
+    logprob: f32,
+    rank: Option<usize>,
+    decoded_token: Option<String>,
+}
+
+/// The candidates of a position, in the order the backend listed them.
+struct Position(Vec<Candidate>);
+
+impl<'de> Deserialize<'de> for Position {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct PositionVisitor;
+
+        impl<'de> Visitor<'de> for PositionVisitor {
+            type Value = Position;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("map of token ids to log probabilities")
+            }
+
+            fn visit_map<M>(self, mut map: M) -> Result<Self::Value, M::Error>
+            where
+                M: MapAccess<'de>,
+            {
+                let mut candidates = Vec::new();
+                while let Some((_, candidate)) = map.next_entry::<String, Candidate>()? {
+                    candidates.push(candidate);
+                }
+                Ok(Position(candidates))
+            }
+        }
+
+        deserializer.deserialize_map(PositionVisitor)
+    }
+}
+
+/// Read the log probabilities of the tokens generated in a response, keeping the `top_logprobs`
+/// most likely tokens of every position.
+pub(crate) fn logprobs_output(
+    response: &ModelInferResponse,
+    top_logprobs: usize,
+) -> anyhow::Result<Vec<TokenLogprob>> {
+    let Some(logprobs) = string_output(response, LOGPROBS)? else {
+        return Ok(Vec::new());
+    };
+    parse_logprobs(&logprobs, top_logprobs)
+}
+
+fn parse_logprobs(logprobs: &str, top_logprobs: usize) -> anyhow::Result<Vec<TokenLogprob>> {
+    let logprobs = replace_infinity(logprobs);
+    let positions: Option<Vec<Position>> =
+        serde_json::from_str(&logprobs).context("failed to deserialize triton output logprobs")?;
+
+    Ok(positions
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|Position(mut candidates)| {
+            if candidates.is_empty() {
+                return None;
+            }
+            let sampled = candidates.remove(0);
+            let mut top: Vec<_> = std::iter::once(&sampled)
+                .chain(&candidates)
+                .filter(|candidate| candidate.rank.is_some_and(|rank| rank <= top_logprobs))
+                .collect();
+            top.sort_by_key(|candidate| candidate.rank);
+            Some(TokenLogprob {
+                top_logprobs: top
+                    .into_iter()
+                    .map(|candidate| {
+                        let token = candidate.decoded_token.clone().unwrap_or_default();
+                        (token, candidate.logprob)
+                    })
+                    .collect(),
+                token: sampled.decoded_token.unwrap_or_default(),
+                logprob: sampled.logprob,
+            })
+        })
+        .collect())
+}
+
+/// Python encodes the log probability of impossible tokens as `-Infinity`, which is not JSON.
+/// Replace it by [`MIN_LOGPROB`] wherever a value is expected, leaving the strings alone.
+fn replace_infinity(json: &str) -> String {
+    const INFINITY: &str = "-Infinity";
+    let mut replaced = String::with_capacity(json.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut rest = json;
+    while let Some(c) = rest.chars().next() {
+        if !in_string && rest.starts_with(INFINITY) {
+            replaced.push_str(&format!("{:?}", MIN_LOGPROB));
+            rest = &rest[INFINITY.len()..];
+            continue;
+        }
+        if escaped {
+            escaped = false;
+        } else if in_string && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            in_string = !in_string;
+        }
+        replaced.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    replaced
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_logprobs() {
+        let logprobs = parse_logprobs(
+            r#"[
+                {"9906": {"logprob": -0.5, "rank": 2, "decoded_token": "Hello"},
+                 "13347": {"logprob": -0.1, "rank": 1, "decoded_token": "Hi"}},
+                {"11": {"logprob": -Infinity, "rank": 3, "decoded_token": ","},
+                 "0": {"logprob": -0.2, "rank": 1, "decoded_token": "!"}}
+            ]"#,
+            2,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                TokenLogprob {
+                    token: "Hello".to_string(),
+                    logprob: -0.5,
+                    top_logprobs: vec![("Hi".to_string(), -0.1), ("Hello".to_string(), -0.5)],
+                },
+                TokenLogprob {
+                    token: ",".to_string(),
+                    logprob: MIN_LOGPROB,
+                    top_logprobs: vec![("!".to_string(), -0.2)],
+                },
+            ],
+            logprobs
+        );
+        assert!(parse_logprobs("null", 2).unwrap().is_empty());
+
+        // Only the values are replaced, whatever their spacing, not the text of the tokens.
+        let logprobs = parse_logprobs(
+            r#"[{"11":{"logprob":-Infinity,"rank":1,"decoded_token":"\": -Infinity"}}]"#,
+            1,
+        )
+        .unwrap();
+        assert_eq!("\": -Infinity", logprobs[0].token);
+        assert_eq!(MIN_LOGPROB, logprobs[0].logprob);
+    }
+}
@@ -0,0 +1,418 @@
+//! Metrics of the traffic served by the gateway.
+//!
+//! Every measurement goes to a Prometheus registry, scraped through `/metrics`, and to the global
+//! OpenTelemetry meter, which exports it to the collector when `--otlp-metrics` is set.
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use opentelemetry::metrics::{Counter, Histogram, Unit, UpDownCounter};
+use opentelemetry::{global, KeyValue};
+use prometheus::{
+    exponential_buckets, histogram_opts, opts, Encoder, HistogramVec, IntCounterVec, IntGaugeVec,
+    Registry, TextEncoder,
+};
+
+/// The metrics of the process, registered on first use.
+pub(crate) fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("metrics are registered once"))
+}
+
+/// Model requested by the client, attached to responses so that requests can be labeled by model.
+#[derive(Clone, Debug)]
+pub(crate) struct RequestModel(pub String);
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    time_to_first_token: HistogramVec,
+    inter_token_latency: HistogramVec,
+    output_tokens_per_second: HistogramVec,
+    generated_tokens: IntCounterVec,
+    in_flight_streams: IntGaugeVec,
+    queue_depth: IntGaugeVec,
+    triton_errors: IntCounterVec,
+    otel: OtelInstruments,
+}
+
+/// The same measurements as the Prometheus collectors, as OpenTelemetry instruments.
+struct OtelInstruments {
+    requests: Counter<u64>,
+    request_duration: Histogram<f64>,
+    time_to_first_token: Histogram<f64>,
+    inter_token_latency: Histogram<f64>,
+    output_tokens_per_second: Histogram<f64>,
+    generated_tokens: Counter<u64>,
+    in_flight_streams: UpDownCounter<i64>,
+    queue_depth: UpDownCounter<i64>,
+    triton_errors: Counter<u64>,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("openai_trtllm".to_string()), None)?;
+        let requests = IntCounterVec::new(
+            opts!("requests_total", "HTTP requests served"),
+            &["route", "model", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            histogram_opts!(
+                "request_duration_seconds",
+                "Time until the response starts, the whole generation for non-streaming requests",
+                exponential_buckets(0.01, 2.0, 14)?
+            ),
+            &["route", "model", "status"],
+        )?;
+        let time_to_first_token = HistogramVec::new(
+            histogram_opts!(
+                "time_to_first_token_seconds",
+                "Time until the first generated output",
+                exponential_buckets(0.005, 2.0, 14)?
+            ),
+            &["route", "model"],
+        )?;
+        let inter_token_latency = HistogramVec::new(
+            histogram_opts!(
+                "inter_token_latency_seconds",
+                "Time between two generated outputs",
+                exponential_buckets(0.001, 2.0, 14)?
+            ),
+            &["route", "model"],
+        )?;
+        let output_tokens_per_second = HistogramVec::new(
+            histogram_opts!(
+                "output_tokens_per_second",
+                "Generated tokens per second of every request",
+                exponential_buckets(1.0, 2.0, 12)?
+            ),
+            &["route", "model"],
+        )?;
+        let generated_tokens = IntCounterVec::new(
+            opts!("generated_tokens_total", "Generated tokens"),
+            &["route", "model"],
+        )?;
+        let in_flight_streams = IntGaugeVec::new(
+            opts!("in_flight_streams", "Streaming responses being sent"),
+            &["route", "model"],
+        )?;
+        let queue_depth = IntGaugeVec::new(
+            opts!(
+                "queue_depth",
+                "Requests waiting for their turn to be sent to Triton"
+            ),
+            &["model"],
+        )?;
+        let triton_errors = IntCounterVec::new(
+            opts!("triton_errors_total", "Errors returned by Triton"),
+            &["triton_model", "code"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(time_to_first_token.clone()))?;
+        registry.register(Box::new(inter_token_latency.clone()))?;
+        registry.register(Box::new(output_tokens_per_second.clone()))?;
+        registry.register(Box::new(generated_tokens.clone()))?;
+        registry.register(Box::new(in_flight_streams.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(triton_errors.clone()))?;
+
+        let meter = global::meter("openai_trtllm");
+        let otel = OtelInstruments {
+            requests: meter.u64_counter("openai_trtllm.requests").init(),
+            request_duration: meter
+                .f64_histogram("openai_trtllm.request.duration")
+                .with_unit(Unit::new("s"))
+                .init(),
+            time_to_first_token: meter
+                .f64_histogram("openai_trtllm.time_to_first_token")
+                .with_unit(Unit::new("s"))
+                .init(),
+            inter_token_latency: meter
+                .f64_histogram("openai_trtllm.inter_token_latency")
+                .with_unit(Unit::new("s"))
+                .init(),
+            output_tokens_per_second: meter
+                .f64_histogram("openai_trtllm.output_tokens_per_second")
+                .init(),
+            generated_tokens: meter.u64_counter("openai_trtllm.generated_tokens").init(),
+            in_flight_streams: meter
+                .i64_up_down_counter("openai_trtllm.in_flight_streams")
+                .init(),
+            queue_depth: meter
+                .i64_up_down_counter("openai_trtllm.queue_depth")
+                .init(),
+            triton_errors: meter.u64_counter("openai_trtllm.triton_errors").init(),
+        };
+
+        Ok(Self {
+            registry,
+            requests,
+            request_duration,
+            time_to_first_token,
+            inter_token_latency,
+            output_tokens_per_second,
+            generated_tokens,
+            in_flight_streams,
+            queue_depth,
+            triton_errors,
+            otel,
+        })
+    }
+
+    fn observe_request(&self, route: &str, model: &str, status: StatusCode, elapsed: Duration) {
+        let status = status.as_str();
+        let labels = [route, model, status];
+        self.requests.with_label_values(&labels).inc();
+        self.request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+
+        let attributes = [
+            KeyValue::new("route", route.to_string()),
+            KeyValue::new("model", model.to_string()),
+            KeyValue::new("status", status.to_string()),
+        ];
+        self.otel.requests.add(1, &attributes);
+        self.otel
+            .request_duration
+            .record(elapsed.as_secs_f64(), &attributes);
+    }
+
+    /// Track the requests of `model` entering (`delta` > 0) or leaving its queue.
+    pub fn queued(&self, model: &str, delta: i64) {
+        self.queue_depth.with_label_values(&[model]).add(delta);
+        self.otel
+            .queue_depth
+            .add(delta, &[KeyValue::new("model", model.to_string())]);
+    }
+
+    /// Count an error of the Triton model, `code` being the gRPC code or `inference` for errors
+    /// reported in the responses.
+    pub fn triton_error(&self, triton_model: &str, code: &str) {
+        self.triton_errors
+            .with_label_values(&[triton_model, code])
+            .inc();
+        self.otel.triton_errors.add(
+            1,
+            &[
+                KeyValue::new("triton_model", triton_model.to_string()),
+                KeyValue::new("code", code.to_string()),
+            ],
+        );
+    }
+
+    /// The metrics in the Prometheus text format.
+    fn render(&self) -> prometheus::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// Measurements of a single generation, from the moment the request was received.
+pub(crate) struct GenerationMetrics {
+    route: &'static str,
+    model: String,
+    start: Instant,
+    last_output: Option<Instant>,
+    streaming: bool,
+}
+
+impl GenerationMetrics {
+    pub fn new(route: &'static str, model: &str, streaming: bool) -> Self {
+        let generation = Self {
+            route,
+            model: model.to_string(),
+            start: Instant::now(),
+            last_output: None,
+            streaming,
+        };
+        if streaming {
+            let metrics = metrics();
+            metrics
+                .in_flight_streams
+                .with_label_values(&[route, model])
+                .inc();
+            metrics
+                .otel
+                .in_flight_streams
+                .add(1, &generation.attributes());
+        }
+        generation
+    }
+
+    fn attributes(&self) -> [KeyValue; 2] {
+        [
+            KeyValue::new("route", self.route),
+            KeyValue::new("model", self.model.clone()),
+        ]
+    }
+
+    /// Record that some output was generated.
+    pub fn output(&mut self) {
+        let metrics = metrics();
+        let now = Instant::now();
+        let labels = [self.route, self.model.as_str()];
+        let (histogram, otel_histogram, since) = match self.last_output {
+            None => (
+                &metrics.time_to_first_token,
+                &metrics.otel.time_to_first_token,
+                self.start,
+            ),
+            Some(last_output) => (
+                &metrics.inter_token_latency,
+                &metrics.otel.inter_token_latency,
+                last_output,
+            ),
+        };
+        let elapsed = (now - since).as_secs_f64();
+        histogram.with_label_values(&labels).observe(elapsed);
+        otel_histogram.record(elapsed, &self.attributes());
+        self.last_output = Some(now);
+    }
+
+    /// Record the number of generated tokens once the generation is complete, when it is known.
+    pub fn finish(&self, completion_tokens: Option<usize>) {
+        let Some(completion_tokens) = completion_tokens else {
+            return;
+        };
+        let metrics = metrics();
+        let labels = [self.route, self.model.as_str()];
+        let attributes = self.attributes();
+        metrics
+            .generated_tokens
+            .with_label_values(&labels)
+            .inc_by(completion_tokens as u64);
+        metrics
+            .otel
+            .generated_tokens
+            .add(completion_tokens as u64, &attributes);
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if completion_tokens > 0 && elapsed > 0.0 {
+            let tokens_per_second = completion_tokens as f64 / elapsed;
+            metrics
+                .output_tokens_per_second
+                .with_label_values(&labels)
+                .observe(tokens_per_second);
+            metrics
+                .otel
+                .output_tokens_per_second
+                .record(tokens_per_second, &attributes);
+        }
+    }
+}
+
+impl Drop for GenerationMetrics {
+    fn drop(&mut self) {
+        if self.streaming {
+            let metrics = metrics();
+            metrics
+                .in_flight_streams
+                .with_label_values(&[self.route, self.model.as_str()])
+                .dec();
+            metrics.otel.in_flight_streams.add(-1, &self.attributes());
+        }
+    }
+}
+
+/// Count the requests and measure their duration, labeled by route, model and status.
+///
+/// Only applies to matched routes, the model label is empty for routes not tied to a model.
+pub(crate) async fn track_requests(req: Request<Body>, next: Next) -> Response {
+    let start = Instant::now();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let response = next.run(req).await;
+    let model = response
+        .extensions()
+        .get::<RequestModel>()
+        .map(|model| model.0.as_str())
+        .unwrap_or_default();
+    metrics().observe_request(&route, model, response.status(), start.elapsed());
+    response
+}
+
+pub(crate) async fn prometheus_metrics() -> Response {
+    match metrics().render() {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(e) => {
+            tracing::error!("failed to encode metrics: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        let text = String::from_utf8(metrics().render().unwrap()).unwrap();
+        text.lines()
+            .filter(|line| line.starts_with(&format!("{}{{", name)))
+            .find(|line| {
+                labels
+                    .iter()
+                    .all(|(key, value)| line.contains(&format!("{}=\"{}\"", key, value)))
+            })
+            .and_then(|line| line.rsplit(' ').next()?.parse().ok())
+    }
+
+    #[test]
+    pub fn test_generation_metrics() {
+        let labels = [("route", "/test"), ("model", "test-generation")];
+        let mut generation = GenerationMetrics::new("/test", "test-generation", true);
+        assert_eq!(
+            Some(1.0),
+            sample("openai_trtllm_in_flight_streams", &labels)
+        );
+
+        generation.output();
+        generation.output();
+        generation.output();
+        generation.finish(Some(12));
+        drop(generation);
+
+        assert_eq!(
+            Some(0.0),
+            sample("openai_trtllm_in_flight_streams", &labels)
+        );
+        assert_eq!(
+            Some(1.0),
+            sample("openai_trtllm_time_to_first_token_seconds_count", &labels)
+        );
+        assert_eq!(
+            Some(2.0),
+            sample("openai_trtllm_inter_token_latency_seconds_count", &labels)
+        );
+        assert_eq!(
+            Some(12.0),
+            sample("openai_trtllm_generated_tokens_total", &labels)
+        );
+    }
+
+    #[test]
+    pub fn test_triton_errors() {
+        metrics().triton_error("test-errors", "Unavailable");
+        metrics().triton_error("test-errors", "Unavailable");
+
+        assert_eq!(
+            Some(2.0),
+            sample(
+                "openai_trtllm_triton_errors_total",
+                &[("triton_model", "test-errors"), ("code", "Unavailable")]
+            )
+        );
+    }
+}
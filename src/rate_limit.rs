@@ -0,0 +1,419 @@
+//! Limits on the requests per minute, tokens per minute and concurrent requests of every client.
+//!
+//! Clients are told apart by the name of their API key, or by their IP address when
+//! authentication is disabled. Both rates are token buckets refilled continuously over a minute.
+//! Tokens are only known once a generation completes, so a request is admitted as long as some
+//! tokens are left and its usage is deducted afterwards.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::auth::Identity;
+use crate::error::AppError;
+
+/// Number of clients above which the clients that are back to their full quota are forgotten.
+const MAX_IDLE_CLIENTS: usize = 1024;
+
+/// Limits of a client, unlimited when not set.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Limits {
+    pub requests_per_minute: Option<u64>,
+    pub tokens_per_minute: Option<u64>,
+    pub concurrent_requests: Option<usize>,
+}
+
+impl Limits {
+    /// The limits set here, falling back to `defaults` for the others.
+    pub fn or(self, defaults: Limits) -> Limits {
+        Limits {
+            requests_per_minute: self.requests_per_minute.or(defaults.requests_per_minute),
+            tokens_per_minute: self.tokens_per_minute.or(defaults.tokens_per_minute),
+            concurrent_requests: self.concurrent_requests.or(defaults.concurrent_requests),
+        }
+    }
+
+    /// Check that the rates can be refilled, a rate of 0 per minute never would.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, limit) in [
+            ("requests_per_minute", self.requests_per_minute),
+            ("tokens_per_minute", self.tokens_per_minute),
+        ] {
+            if limit == Some(0) {
+                anyhow::bail!("{} must be at least 1", name);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+/// A rate limit refilled continuously, one minute to go from empty to full.
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: u64, now: Instant) -> Self {
+        Self {
+            capacity: capacity as f64,
+            available: capacity as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = (now - self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Time until `amount` is available.
+    fn wait(&self, amount: f64) -> Duration {
+        Duration::from_secs_f64((amount - self.available).max(0.0) * 60.0 / self.capacity)
+    }
+
+    fn remaining(&self) -> u64 {
+        self.available.max(0.0) as u64
+    }
+
+    fn is_full(&self) -> bool {
+        self.available >= self.capacity
+    }
+}
+
+#[derive(Default, Debug)]
+struct Client {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+    concurrent: usize,
+}
+
+impl Client {
+    /// Apply the current limits of the client, which change when the key file is reloaded.
+    fn update(&mut self, limits: &Limits, now: Instant) {
+        for (bucket, limit) in [
+            (&mut self.requests, limits.requests_per_minute),
+            (&mut self.tokens, limits.tokens_per_minute),
+        ] {
+            match limit {
+                Some(limit) => {
+                    let bucket = bucket.get_or_insert_with(|| Bucket::new(limit, now));
+                    bucket.capacity = limit as f64;
+                    bucket.refill(now);
+                }
+                None => *bucket = None,
+            }
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        for bucket in [&mut self.requests, &mut self.tokens].into_iter().flatten() {
+            bucket.refill(now);
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.concurrent == 0
+            && self.requests.as_ref().is_none_or(Bucket::is_full)
+            && self.tokens.as_ref().is_none_or(Bucket::is_full)
+    }
+
+    /// The `x-ratelimit-*` headers describing the state of the limits.
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, bucket) in [("requests", &self.requests), ("tokens", &self.tokens)] {
+            let Some(bucket) = bucket else {
+                continue;
+            };
+            let mut insert = |header: &str, value: String| {
+                headers.insert(
+                    HeaderName::try_from(format!("x-ratelimit-{}-{}", header, name)).unwrap(),
+                    HeaderValue::try_from(value).unwrap(),
+                );
+            };
+            insert("limit", (bucket.capacity as u64).to_string());
+            insert("remaining", bucket.remaining().to_string());
+            insert("reset", format_duration(bucket.wait(bucket.capacity)));
+        }
+        headers
+    }
+}
+
+/// Format a duration the way OpenAI does in the `x-ratelimit-reset-*` headers, e.g. `6m0s`.
+fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        return format!("{}ms", duration.as_millis());
+    }
+    let secs = duration.as_secs_f64().ceil() as u64;
+    if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m{}s", secs / 60, secs % 60)
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    defaults: Limits,
+    clients: Arc<Mutex<HashMap<String, Client>>>,
+}
+
+impl RateLimiter {
+    /// Apply `defaults` to every client whose API key does not set its own limits.
+    pub fn new(defaults: Limits) -> Self {
+        Self {
+            defaults,
+            clients: Default::default(),
+        }
+    }
+
+    /// Admit a request of `client`, or return the error telling it to slow down.
+    fn acquire(&self, client: &str, limits: &Limits) -> Result<(Quota, HeaderMap), RateLimited> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() > MAX_IDLE_CLIENTS {
+            clients.retain(|_, state| {
+                state.refill(now);
+                !state.is_idle()
+            });
+        }
+        let state = clients.entry(client.to_string()).or_default();
+        state.update(limits, now);
+
+        let rejection = |message: String, retry_after: Option<Duration>| {
+            tracing::info!("rate limited {}: {}", client, message);
+            let mut headers = state.headers();
+            if let Some(retry_after) = retry_after {
+                let secs = retry_after.as_secs_f64().ceil() as u64;
+                headers.insert(header::RETRY_AFTER, HeaderValue::from(secs.max(1)));
+            }
+            RateLimited { message, headers }
+        };
+
+        if let (Some(limit), Some(bucket)) = (limits.requests_per_minute, &state.requests) {
+            if bucket.available < 1.0 {
+                let wait = bucket.wait(1.0);
+                return Err(rejection(
+                    format!(
+                        "Rate limit reached for requests per minute: Limit {}. Please try again in {}.",
+                        limit,
+                        format_duration(wait)
+                    ),
+                    Some(wait),
+                ));
+            }
+        }
+        if let (Some(limit), Some(bucket)) = (limits.tokens_per_minute, &state.tokens) {
+            if bucket.available < 1.0 {
+                let wait = bucket.wait(1.0);
+                return Err(rejection(
+                    format!(
+                        "Rate limit reached for tokens per minute: Limit {}. Please try again in {}.",
+                        limit,
+                        format_duration(wait)
+                    ),
+                    Some(wait),
+                ));
+            }
+        }
+        if let Some(limit) = limits.concurrent_requests {
+            if state.concurrent >= limit {
+                return Err(rejection(
+                    format!(
+                        "Too many concurrent requests: Limit {}. Please try again once a request completes.",
+                        limit
+                    ),
+                    None,
+                ));
+            }
+        }
+
+        if let Some(bucket) = state.requests.as_mut() {
+            bucket.available -= 1.0;
+        }
+        state.concurrent += 1;
+        let quota = Quota(Arc::new(QuotaInner {
+            limiter: self.clone(),
+            client: client.to_string(),
+        }));
+        Ok((quota, state.headers()))
+    }
+}
+
+/// A request rejected because the client exceeded one of its limits.
+#[derive(Debug)]
+struct RateLimited {
+    message: String,
+    headers: HeaderMap,
+}
+
+impl IntoResponse for RateLimited {
+    fn into_response(self) -> Response {
+        let mut response = AppError::new(StatusCode::TOO_MANY_REQUESTS, self.message)
+            .with_code("rate_limit_exceeded")
+            .into_response();
+        response.headers_mut().extend(self.headers);
+        response
+    }
+}
+
+/// The admission of a request, counted as concurrent until every clone is dropped.
+#[derive(Clone)]
+pub(crate) struct Quota(Arc<QuotaInner>);
+
+struct QuotaInner {
+    limiter: RateLimiter,
+    client: String,
+}
+
+impl Quota {
+    /// Deduct the tokens used by the request from the tokens per minute of the client.
+    pub fn consume_tokens(&self, tokens: usize) {
+        let mut clients = self.0.limiter.clients.lock().unwrap();
+        if let Some(bucket) = clients
+            .get_mut(&self.0.client)
+            .and_then(|state| state.tokens.as_mut())
+        {
+            bucket.available -= tokens as f64;
+        }
+    }
+}
+
+impl Drop for QuotaInner {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap();
+        if let Some(state) = clients.get_mut(&self.client) {
+            state.concurrent = state.concurrent.saturating_sub(1);
+        }
+    }
+}
+
+pub(crate) async fn rate_limit_middleware(
+    State(limiter): State<RateLimiter>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    let identity = req.extensions().get::<Identity>();
+    let limits = match identity {
+        Some(identity) => identity.limits.or(limiter.defaults),
+        None => limiter.defaults,
+    };
+    if limits.is_unlimited() {
+        return next.run(req).await;
+    }
+
+    let client = match identity {
+        Some(identity) => format!("key:{}", identity.name),
+        None => match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(address)) => format!("ip:{}", address.ip()),
+            None => "anonymous".to_string(),
+        },
+    };
+    let (quota, headers) = match limiter.acquire(&client, &limits) {
+        Ok(admission) => admission,
+        Err(rate_limited) => return rate_limited.into_response(),
+    };
+
+    req.extensions_mut().insert(quota);
+    let mut response = next.run(req).await;
+    response.headers_mut().extend(headers);
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_requests_per_minute() {
+        let limiter = RateLimiter::new(Limits {
+            requests_per_minute: Some(2),
+            ..Default::default()
+        });
+
+        let (_, headers) = limiter.acquire("a", &limiter.defaults).unwrap();
+        assert_eq!("1", headers["x-ratelimit-remaining-requests"]);
+        assert!(limiter.acquire("a", &limiter.defaults).is_ok());
+        let response = limiter
+            .acquire("a", &limiter.defaults)
+            .err()
+            .unwrap()
+            .into_response();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!("2", response.headers()["x-ratelimit-limit-requests"]);
+        assert_eq!("30", response.headers()["retry-after"]);
+        // Every client has its own quota.
+        assert!(limiter.acquire("b", &limiter.defaults).is_ok());
+    }
+
+    #[test]
+    pub fn test_tokens_per_minute() {
+        let limiter = RateLimiter::new(Limits {
+            tokens_per_minute: Some(100),
+            ..Default::default()
+        });
+
+        let (quota, _) = limiter.acquire("a", &limiter.defaults).unwrap();
+        quota.consume_tokens(150);
+        let response = limiter
+            .acquire("a", &limiter.defaults)
+            .err()
+            .unwrap()
+            .into_response();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!("0", response.headers()["x-ratelimit-remaining-tokens"]);
+    }
+
+    #[test]
+    pub fn test_concurrent_requests() {
+        let limiter = RateLimiter::new(Limits {
+            concurrent_requests: Some(1),
+            ..Default::default()
+        });
+
+        let (quota, _) = limiter.acquire("a", &limiter.defaults).unwrap();
+        let stream = quota.clone();
+        assert!(limiter.acquire("a", &limiter.defaults).is_err());
+        drop(quota);
+        assert!(limiter.acquire("a", &limiter.defaults).is_err());
+        drop(stream);
+        assert!(limiter.acquire("a", &limiter.defaults).is_ok());
+    }
+
+    #[test]
+    pub fn test_validate() {
+        assert!(Limits::default().validate().is_ok());
+        for limits in [
+            Limits {
+                requests_per_minute: Some(0),
+                ..Default::default()
+            },
+            Limits {
+                tokens_per_minute: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(limits.validate().is_err());
+        }
+    }
+
+    #[test]
+    pub fn test_format_duration() {
+        assert_eq!("20ms", format_duration(Duration::from_millis(20)));
+        assert_eq!("2s", format_duration(Duration::from_millis(1500)));
+        assert_eq!("6m0s", format_duration(Duration::from_secs(360)));
+    }
+}
@@ -0,0 +1,228 @@
+//! Mapping of the model names used by clients to the Triton models serving them.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::config::{Backend, Config};
+use crate::fim::FimTemplate;
+use crate::history::HistoryBuilder;
+use crate::triton::pool::EndpointPool;
+
+/// Name of the models that are not configured in the metrics and the admission queues, so that
+/// clients can't create one per model name they send.
+pub const UNKNOWN_MODEL: &str = "unknown";
+
+/// Where and how to serve the requests for a model.
+#[derive(Clone)]
+pub struct ModelRoute {
+    pub triton_model: String,
+    /// Empty to let Triton pick the version according to its version policy.
+    pub triton_model_version: String,
+    pub backend: Backend,
+    pub pool: EndpointPool,
+    pub history_builder: HistoryBuilder,
+    /// Renders the prompt of completions with a suffix, which are rejected without it.
+    pub fim_template: Option<FimTemplate>,
+    /// Whether prompts given as token ids are sent as such, rather than decoded.
+    pub input_ids: bool,
+    /// Sampling parameters applied when the request does not set them.
+    pub sampling_parameters: serde_json::Map<String, serde_json::Value>,
+    /// Whether the model is declared by an alias or in the config file, rather than named by the
+    /// client only.
+    pub configured: bool,
+}
+
+impl ModelRoute {
+    /// The Triton model in the metrics.
+    pub fn triton_model_label(&self) -> &str {
+        if self.configured {
+            &self.triton_model
+        } else {
+            UNKNOWN_MODEL
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ModelRegistry {
+    backend: Backend,
+    pool: EndpointPool,
+    history_builder: HistoryBuilder,
+    fim_template: Option<FimTemplate>,
+    routes: Arc<BTreeMap<String, ModelRoute>>,
+    /// Every distinct pool of Triton endpoints, the default one first.
+    pools: Arc<Vec<EndpointPool>>,
+}
+
+impl ModelRegistry {
+    /// Build the routes of the models declared in the config. Any other model is served by the
+    /// default endpoint and template.
+    pub fn new(
+        config: &Config,
+        pool: EndpointPool,
+        history_builder: HistoryBuilder,
+    ) -> anyhow::Result<Self> {
+        let fim_template = config
+            .fim_template
+            .as_deref()
+            .map(FimTemplate::new)
+            .transpose()
+            .context("invalid fim template")?;
+        let default_route = |triton_model: &str| ModelRoute {
+            triton_model: triton_model.to_string(),
+            triton_model_version: String::new(),
+            backend: config.backend,
+            pool: pool.clone(),
+            history_builder: history_builder.clone(),
+            fim_template: fim_template.clone(),
+            input_ids: false,
+            sampling_parameters: Default::default(),
+            configured: true,
+        };
+
+        let mut routes = BTreeMap::new();
+        for alias in &config.model_alias {
+            let (alias, model) = parse_model_alias(alias)?;
+            routes.insert(alias.to_string(), default_route(model));
+        }
+
+        let mut pools = HashMap::from([(config.triton_endpoint.clone(), pool.clone())]);
+        for (name, model) in &config.models {
+            let mut route = default_route(model.triton_model.as_deref().unwrap_or(name));
+            if let Some(version) = &model.triton_model_version {
+                route.triton_model_version = version.clone();
+            }
+            if let Some(backend) = model.backend {
+                route.backend = backend;
+            }
+            if !model.triton_endpoint.is_empty() {
+                let endpoints = &model.triton_endpoint;
+                if !pools.contains_key(endpoints) {
+                    let pool = EndpointPool::new(
+                        endpoints,
+                        config.load_balancing,
+                        Duration::from_secs(config.ejection_secs),
+                    )?;
+                    pools.insert(endpoints.clone(), pool);
+                }
+                route.pool = pools[endpoints].clone();
+            }
+            if model.history_template.is_some() || model.history_template_file.is_some() {
+                route.history_builder =
+                    HistoryBuilder::new(&model.history_template, &model.history_template_file)
+                        .with_context(|| format!("invalid history template of model {}", name))?;
+            }
+            if let Some(template) = &model.fim_template {
+                route.fim_template = Some(
+                    FimTemplate::new(template)
+                        .with_context(|| format!("invalid fim template of model {}", name))?,
+                );
+            }
+            route.input_ids = model.input_ids;
+            route.sampling_parameters = model.sampling_parameters.clone();
+            routes.insert(name.clone(), route);
+        }
+
+        pools.remove(&config.triton_endpoint);
+        let pools = std::iter::once(pool.clone())
+            .chain(pools.into_values())
+            .collect();
+        Ok(Self {
+            backend: config.backend,
+            pool,
+            history_builder,
+            fim_template,
+            routes: Arc::new(routes),
+            pools: Arc::new(pools),
+        })
+    }
+
+    /// The route of `model`, models that are not configured are served under their own name.
+    pub fn resolve(&self, model: &str) -> ModelRoute {
+        self.routes
+            .get(model)
+            .cloned()
+            .unwrap_or_else(|| ModelRoute {
+                triton_model: model.to_string(),
+                triton_model_version: String::new(),
+                backend: self.backend,
+                pool: self.pool.clone(),
+                history_builder: self.history_builder.clone(),
+                fim_template: self.fim_template.clone(),
+                input_ids: false,
+                sampling_parameters: Default::default(),
+                configured: false,
+            })
+    }
+
+    /// The name of `model` in the metrics and the admission queues.
+    pub fn label<'a>(&self, model: &'a str) -> &'a str {
+        if self.routes.contains_key(model) {
+            model
+        } else {
+            UNKNOWN_MODEL
+        }
+    }
+
+    /// The models declared by aliases or in the config file.
+    pub fn configured(&self) -> impl Iterator<Item = (&String, &ModelRoute)> {
+        self.routes.iter()
+    }
+
+    /// Start polling the readiness of every Triton endpoint in the background.
+    pub fn watch(&self) {
+        for pool in self.pools.iter() {
+            pool.watch();
+        }
+    }
+
+    /// Every distinct pool of Triton endpoints.
+    pub fn pools(&self) -> &[EndpointPool] {
+        &self.pools
+    }
+
+    /// Replicas of the default Triton endpoint.
+    pub fn pool(&self) -> &EndpointPool {
+        &self.pool
+    }
+}
+
+fn parse_model_alias(alias: &str) -> anyhow::Result<(&str, &str)> {
+    match alias.split_once('=') {
+        Some((alias, model)) if !alias.is_empty() && !model.is_empty() => Ok((alias, model)),
+        _ => anyhow::bail!("model alias must be given as ALIAS=MODEL, got {:?}", alias),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_label() {
+        let config =
+            Config::parse_from(["openai_trtllm", "--model-alias", "gpt-3.5-turbo=vllm_model"]);
+        let pool = EndpointPool::new(
+            &config.triton_endpoint,
+            config.load_balancing,
+            Duration::from_secs(config.ejection_secs),
+        )
+        .unwrap();
+        let history_builder =
+            HistoryBuilder::new(&config.history_template, &config.history_template_file).unwrap();
+        let models = ModelRegistry::new(&config, pool, history_builder).unwrap();
+
+        assert_eq!("gpt-3.5-turbo", models.label("gpt-3.5-turbo"));
+        assert_eq!(
+            "vllm_model",
+            models.resolve("gpt-3.5-turbo").triton_model_label()
+        );
+        // Models named by the client only share one label.
+        assert_eq!(UNKNOWN_MODEL, models.label("random"));
+        assert_eq!(UNKNOWN_MODEL, models.resolve("random").triton_model_label());
+    }
+}
@@ -0,0 +1,92 @@
+//! Validation of model output requested to be JSON through `response_format`.
+use axum::http::StatusCode;
+use jsonschema::JSONSchema;
+
+use crate::error::{AppError, InvalidRequest};
+
+pub(crate) enum JsonOutputValidator {
+    /// Any syntactically valid JSON object is accepted.
+    Object,
+    /// The output must match the given JSON schema.
+    Schema(Box<JSONSchema>),
+}
+
+impl JsonOutputValidator {
+    pub fn new(schema: Option<&serde_json::Value>) -> anyhow::Result<Self> {
+        match schema {
+            None => Ok(Self::Object),
+            Some(schema) => JSONSchema::compile(schema)
+                .map(|schema| Self::Schema(Box::new(schema)))
+                .map_err(|e| {
+                    InvalidRequest::param(
+                        "response_format",
+                        format!("invalid JSON schema in response_format: {}", e),
+                    )
+                    .into()
+                }),
+        }
+    }
+
+    /// Check the output of the model, the error tells the client which check failed.
+    pub fn validate(&self, content: &str) -> Result<(), AppError> {
+        let value: serde_json::Value = serde_json::from_str(content)
+            .map_err(|e| invalid_output(format!("model output is not valid JSON: {}", e)))?;
+        match self {
+            Self::Object if value.is_object() => Ok(()),
+            Self::Object => Err(invalid_output(
+                "model output is not a JSON object".to_string(),
+            )),
+            Self::Schema(schema) => schema.validate(&value).map_err(|errors| {
+                let errors: Vec<_> = errors
+                    .map(|e| format!("{} at '{}'", e, e.instance_path))
+                    .collect();
+                invalid_output(format!(
+                    "model output does not match the JSON schema: {}",
+                    errors.join("; ")
+                ))
+            }),
+        }
+    }
+}
+
+fn invalid_output(message: String) -> AppError {
+    tracing::warn!("{}", message);
+    AppError::new(StatusCode::INTERNAL_SERVER_ERROR, message).with_code("invalid_model_output")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_json_object() {
+        let validator = JsonOutputValidator::new(None).unwrap();
+        assert!(validator.validate(r#"{"a": 1}"#).is_ok());
+        assert!(validator.validate("[1, 2]").is_err());
+        assert!(validator.validate(r#"{"a": "#).is_err());
+    }
+
+    #[test]
+    pub fn test_json_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"name": {"type": "string"}},
+            "required": ["name"]
+        });
+        let validator = JsonOutputValidator::new(Some(&schema)).unwrap();
+        assert!(validator.validate(r#"{"name": "Paris"}"#).is_ok());
+
+        let error = validator.validate(r#"{"name": 1}"#).unwrap_err();
+        assert_eq!(
+            "model output does not match the JSON schema: 1 is not of type \"string\" at '/name'",
+            error.body()["error"]["message"]
+        );
+        assert_eq!("invalid_model_output", error.body()["error"]["code"]);
+    }
+
+    #[test]
+    pub fn test_invalid_schema() {
+        assert!(JsonOutputValidator::new(Some(&json!({"type": 1}))).is_err());
+    }
+}
@@ -1,139 +1,265 @@
 //! https://platform.openai.com/docs/api-reference/chat/create
 use std::collections::HashMap;
-use std::iter::IntoIterator;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
-use async_stream::{stream, try_stream};
+use async_stream::try_stream;
 use axum::extract::State;
 use axum::http::HeaderMap;
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::Json;
+use axum::{Extension, Json};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tonic::codegen::tokio_stream::Stream;
-use tonic::transport::Channel;
 use tracing;
 use tracing::instrument;
 use uuid::Uuid;
 
-use crate::error::AppError;
-use crate::history::HistoryBuilder;
+use crate::admission::Permit;
+use crate::auth::{authorize_model, Identity};
+use crate::config::Backend;
+use crate::error::{AppError, AppJson, InvalidRequest};
+use crate::finish_reason::FinishReasonTracker;
+use crate::logprobs::{logprobs_output, request_logprobs, TokenLogprob};
+use crate::metrics::{GenerationMetrics, RequestModel};
+use crate::rate_limit::Quota;
+use crate::registry::ModelRoute;
+use crate::response_format::JsonOutputValidator;
+use crate::sampling::SamplingParams;
 use crate::state::AppState;
-use crate::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
+use crate::stop::StopMatcher;
+use crate::stream_options::{include_usage, StreamOptions};
+use crate::tool_calls::{ParsedOutput, ToolCallParser};
 use crate::triton::request::{Builder, InferTensorData};
-use crate::triton::telemetry::propagate_context;
-use crate::triton::ModelInferRequest;
-use crate::utils::deserialize_bytes_tensor;
+use crate::triton::response::string_output;
+use crate::triton::streams::{model_stream_infer_all, Deadline};
+use crate::triton::{ModelInferRequest, ModelInferResponse};
+use crate::usage::{additional_outputs, TokenCounter, UsageTracker};
+use crate::utils::string_or_seq_string;
 
-#[instrument(name = "chat_completions", skip(grpc_client, history_builder, request))]
+/// Route of the endpoint, as the label of its metrics.
+const ROUTE: &str = "/v1/chat/completions";
+
+/// Most likely tokens whose log probabilities can be returned, as OpenAI allows.
+const MAX_TOP_LOGPROBS: usize = 20;
+
+#[instrument(name = "chat_completions", skip(state, identity, quota, request), fields(user = ?request.user))]
 pub(crate) async fn compat_chat_completions(
     headers: HeaderMap,
-    State(AppState {
-        grpc_client,
-        history_builder,
-    }): State<AppState>,
-    request: Json<ChatCompletionCreateParams>,
+    State(state): State<AppState>,
+    identity: Option<Extension<Identity>>,
+    quota: Option<Extension<Quota>>,
+    AppJson(request): AppJson<ChatCompletionCreateParams>,
 ) -> Response {
     tracing::info!("request: {:?}", request);
 
-    if request.stream {
-        chat_completions_stream(headers, grpc_client, history_builder, request)
-            .await
-            .into_response()
-    } else {
-        chat_completions(headers, grpc_client, history_builder, request)
-            .await
-            .into_response()
+    let quota = quota.map(|Extension(quota)| quota);
+    let label = state.models.label(&request.model).to_string();
+    let model = RequestModel(label.clone());
+    let mut response = match admit(&state, &identity, &request, &label).await {
+        Err(e) => e.into_response(),
+        Ok((inference, permit)) if request.stream => {
+            chat_completions_stream(headers, state, quota, permit, request, inference)
+                .await
+                .into_response()
+        }
+        Ok((inference, permit)) => {
+            chat_completions(headers, state, quota, permit, request, inference)
+                .await
+                .into_response()
+        }
+    };
+    response.extensions_mut().insert(model);
+    response
+}
+
+/// Check the request, then wait for its turn, so that invalid requests are rejected right away.
+async fn admit(
+    state: &AppState,
+    identity: &Option<Extension<Identity>>,
+    request: &ChatCompletionCreateParams,
+    label: &str,
+) -> Result<(Inference, Permit), AppError> {
+    authorize_model(identity, &request.model)?;
+    let inference = Inference::new(request, state)?;
+    let permit = state.admission.admit(identity, label).await?;
+    Ok((inference, permit))
+}
+
+/// The inference of a request, checked before the request is admitted.
+struct Inference {
+    model: ModelRoute,
+    prompt: String,
+    sampling_params: SamplingParams,
+    /// The Triton request of every choice.
+    requests: Vec<ModelInferRequest>,
+    include_usage: bool,
+    json_output_validator: Option<JsonOutputValidator>,
+}
+
+impl Inference {
+    fn new(request: &ChatCompletionCreateParams, state: &AppState) -> anyhow::Result<Self> {
+        let include_usage = include_usage(request.stream, &request.stream_options)?;
+        // JSON output is not validated while streaming, but reject invalid schemas all the same.
+        let json_output_validator = json_output_validator(request)?;
+        let model = state.models.resolve(&request.model);
+        let prompt = build_prompt(request, &model)?;
+        let sampling_params = sampling_params(request, &model)?;
+        let requests = build_triton_requests(
+            request,
+            &model,
+            &prompt,
+            &sampling_params,
+            state.vllm_additional_outputs,
+            state.max_n,
+        )?;
+        Ok(Self {
+            model,
+            prompt,
+            sampling_params,
+            requests,
+            include_usage,
+            json_output_validator,
+        })
     }
 }
 
 #[instrument(
     name = "streaming chat completions",
-    skip(client, history_builder, request)
+    skip(state, quota, permit, request, inference)
 )]
 async fn chat_completions_stream(
     headers: HeaderMap,
-    mut client: GrpcInferenceServiceClient<Channel>,
-    history_builder: HistoryBuilder,
-    Json(request): Json<ChatCompletionCreateParams>,
+    state: AppState,
+    quota: Option<Quota>,
+    permit: Permit,
+    request: ChatCompletionCreateParams,
+    inference: Inference,
 ) -> Result<Sse<impl Stream<Item = anyhow::Result<Event>>>, AppError> {
+    let mut generation = GenerationMetrics::new(ROUTE, state.models.label(&request.model), true);
+    let deadline = Deadline::new(state.timeouts);
     let id = format!("cmpl-{}", Uuid::new_v4());
     let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
     let model_name = request.model.clone();
-    let request = build_triton_request(request, &history_builder)?;
+    let Inference {
+        model,
+        prompt,
+        sampling_params,
+        requests,
+        include_usage,
+        ..
+    } = inference;
+    let mut choices: Vec<_> = (0..request.n)
+        .map(|_| ChoiceState::new(&request, &state, &sampling_params))
+        .collect();
+    let triton_model_label = model.triton_model_label().to_string();
+    let pool = model.pool;
+    let token_counter = state.token_counter;
 
-    let response_stream = try_stream! {
-        let request = stream! { yield request };
-        let mut request = tonic::Request::new(request);
+    let chunk = move |choices: Vec<ChatCompletionChunkChoice>, usage: Option<Usage>| {
+        let response = ChatCompletionChunk {
+            id: id.clone(),
+            object: "text_completion".to_string(),
+            created,
+            model: model_name.clone(),
+            system_fingerprint: None,
+            choices,
+            // Every chunk but the last one has a null usage when it is included.
+            usage: include_usage.then_some(usage),
+        };
+        Event::default().json_data(response).unwrap()
+    };
 
-        propagate_context(&mut request, &headers);
+    let response_stream = try_stream! {
+        // Held by the stream, the next request is let in once the client is done with it.
+        let _permit = permit;
+        let mut streams = model_stream_infer_all(&pool, &headers, requests, &triton_model_label, deadline);
 
-        let mut stream = client
-            .model_stream_infer(request)
-            .await
-            .context("failed to call triton grpc method model_stream_infer")?
-            .into_inner();
+        loop {
+            let (index, response) = match deadline.next(&mut streams).await {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                // The generation is cancelled as the responses are dropped.
+                Err(e) => {
+                    yield AppError::from(e).event();
+                    return;
+                }
+            };
+            let choice = &mut choices[index];
+            if let Some(response) = response {
+                // Also the errors of the inferences that failed to start.
+                let response = match response {
+                    Ok(response) => response,
+                    Err(status) => {
+                        yield AppError::from(status).event();
+                        return;
+                    }
+                };
+                if !response.error_message.is_empty() {
+                    tracing::error!("received error message from triton: {}", response.error_message);
 
-        while let Some(response) = stream.message().await? {
-            if !response.error_message.is_empty() {
-                tracing::error!("received error message from triton: {}", response.error_message);
+                    yield AppError::from_triton_message(&response.error_message).event();
+                    return;
+                }
+                let infer_response = response
+                    .infer_response
+                    .context("empty infer response received")?;
+                tracing::debug!("triton infer response: {:?}", infer_response);
 
-                // Corresponds to https://github.com/openai/openai-python/blob/17ac6779958b2b74999c634c4ea4c7b74906027a/src/openai/_streaming.py#L113
-                yield Event::default().event("error").json_data(json!({
-                    "error": {
-                        "status_code": 500,
-                        "message": "Internal Server Error"
-                    }
-                })).unwrap();
-                return;
+                let outputs = choice.push(&infer_response)?;
+                if !outputs.is_empty() {
+                    generation.output();
+                }
+                for output in outputs {
+                    let delta = choice.delta(output);
+                    yield chunk(vec![ChatCompletionChunkChoice {
+                        index,
+                        delta,
+                        logprobs: choice.take_logprobs(),
+                        finish_reason: None,
+                    }], None);
+                }
+                if !choice.is_stopped() {
+                    continue;
+                }
+                // A stop sequence was generated, the rest of this choice is not needed.
+                streams.remove(&index);
             }
-            let infer_response = response
-                .infer_response
-                .context("empty infer response received")?;
-            tracing::debug!("triton infer response: {:?}", infer_response);
 
-            let raw_content = infer_response.raw_output_contents[0].clone();
-            let content = deserialize_bytes_tensor(raw_content)?.into_iter().collect::<String>();
-            tracing::debug!("deserialized triton infer response content: {:?}", content);
-
-            if !content.is_empty() {
-                let response = ChatCompletionChunk {
-                    id: id.clone(),
-                    object: "text_completion".to_string(),
-                    created,
-                    model: model_name.clone(),
-                    system_fingerprint: None,
-                    choices: vec![ChatCompletionChunkChoice {
-                        index: 0,
-                        delta: ChatCompletionChunkDelta {
-                            role: Some(Role::Assistant),
-                            content: Some(content),
-                        },
-                        finish_reason: None,
-                    }],
-                };
-                yield Event::default().json_data(response).unwrap();
+            // The choice is complete, flush whatever is left.
+            for output in choice.finish() {
+                let delta = choice.delta(output);
+                yield chunk(vec![ChatCompletionChunkChoice {
+                    index,
+                    delta,
+                    logprobs: choice.take_logprobs(),
+                    finish_reason: None,
+                }], None);
             }
-        }
-        let response = ChatCompletionChunk {
-            id,
-            object: "text_completion".to_string(),
-            created,
-            model: model_name,
-            system_fingerprint: None,
-            choices: vec![ChatCompletionChunkChoice {
-                index: 0,
+            yield chunk(vec![ChatCompletionChunkChoice {
+                index,
                 delta: ChatCompletionChunkDelta {
                     role: None,
                     content: None,
+                    tool_calls: None,
                 },
-                finish_reason: Some(FinishReason::Stop),
-            }],
-        };
-        yield Event::default().json_data(response).unwrap();
+                logprobs: choice.take_logprobs(),
+                finish_reason: Some(choice.finish_reason(&token_counter)),
+            }], None);
+        }
+        generation.finish(completion_tokens(&choices, &token_counter));
+        let usage = total_usage(&choices, &token_counter, &prompt);
+        if let Some(quota) = &quota {
+            quota.consume_tokens(usage.total_tokens);
+        }
+
+        if include_usage {
+            // The usage statistics for the entire request are sent in an extra chunk with an
+            // empty choices list.
+            yield chunk(vec![], Some(usage));
+        }
 
         // OpenAI stream response terminated by a data: [DONE] message.
         yield Event::default().data("[DONE]");
@@ -144,49 +270,77 @@ async fn chat_completions_stream(
 
 #[instrument(
     name = "non-streaming chat completions",
-    skip(client, history_builder, request),
+    skip(state, quota, permit, request, inference),
     err(Debug)
 )]
 async fn chat_completions(
     headers: HeaderMap,
-    mut client: GrpcInferenceServiceClient<Channel>,
-    history_builder: HistoryBuilder,
-    Json(request): Json<ChatCompletionCreateParams>,
+    state: AppState,
+    quota: Option<Quota>,
+    permit: Permit,
+    request: ChatCompletionCreateParams,
+    inference: Inference,
 ) -> Result<Json<ChatCompletion>, AppError> {
+    let mut generation = GenerationMetrics::new(ROUTE, state.models.label(&request.model), false);
+    let deadline = Deadline::new(state.timeouts.unstreamed());
     let model_name = request.model.clone();
+    let Inference {
+        model,
+        prompt,
+        sampling_params,
+        requests,
+        json_output_validator,
+        ..
+    } = inference;
+    let mut choices: Vec<_> = (0..request.n)
+        .map(|_| ChoiceState::new(&request, &state, &sampling_params))
+        .collect();
+    let mut outputs: Vec<Vec<ParsedOutput>> = (0..request.n).map(|_| Vec::new()).collect();
 
-    let request = build_triton_request(request, &history_builder)?;
-    let request = stream! { yield request };
-    let mut request = tonic::Request::new(request);
-
-    propagate_context(&mut request, &headers);
-
-    let mut stream = client
-        .model_stream_infer(request)
-        .await
-        .context("failed to call triton grpc method model_stream_infer")?
-        .into_inner();
+    let mut streams = model_stream_infer_all(
+        &model.pool,
+        &headers,
+        requests,
+        model.triton_model_label(),
+        deadline,
+    );
+    while let Some((index, response)) = deadline.next(&mut streams).await? {
+        let choice = &mut choices[index];
+        if let Some(response) = response {
+            let response = response?;
+            if !response.error_message.is_empty() {
+                return Err(AppError::from_triton_message(&response.error_message));
+            }
+            let infer_response = response
+                .infer_response
+                .context("empty infer response received")?;
+            tracing::debug!("triton infer response: {:?}", infer_response);
 
-    let mut contents: Vec<String> = Vec::new();
-    while let Some(response) = stream.message().await? {
-        if !response.error_message.is_empty() {
-            return Err(anyhow::anyhow!(
-                "error message received from triton: {}",
-                response.error_message
-            )
-            .into());
+            let output = choice.push(&infer_response)?;
+            if !output.is_empty() {
+                generation.output();
+            }
+            outputs[index].extend(output);
+            if !choice.is_stopped() {
+                continue;
+            }
+            streams.remove(&index);
         }
-        let infer_response = response
-            .infer_response
-            .context("empty infer response received")?;
-        tracing::debug!("triton infer response: {:?}", infer_response);
-
-        let raw_content = infer_response.raw_output_contents[0].clone();
-        let content = deserialize_bytes_tensor(raw_content)?.into_iter().collect();
-        tracing::debug!("deserialized triton infer response content: {:?}", content);
+        outputs[index].extend(choice.finish());
+    }
+    drop(permit);
+    generation.finish(completion_tokens(&choices, &state.token_counter));
 
-        contents.push(content);
+    let usage = total_usage(&choices, &state.token_counter, &prompt);
+    if let Some(quota) = &quota {
+        quota.consume_tokens(usage.total_tokens);
     }
+    let choices = completion_choices(
+        &choices,
+        outputs,
+        json_output_validator.as_ref(),
+        &state.token_counter,
+    )?;
 
     Ok(Json(ChatCompletion {
         id: format!("cmpl-{}", Uuid::new_v4()),
@@ -194,52 +348,357 @@ async fn chat_completions(
         created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
         model: model_name,
         system_fingerprint: None,
-        choices: vec![ChatCompletionChoice {
-            index: 0,
-            message: ChatCompletionMessage {
-                role: Role::Assistant,
-                content: Some(contents.into_iter().collect()),
-            },
-            finish_reason: Some(FinishReason::Stop),
-        }],
-        // Not supported yet, need triton to return usage stats
-        // but add a fake one to make LangChain happy
-        usage: Some(Usage {
-            prompt_tokens: 0,
-            completion_tokens: 0,
-            total_tokens: 0,
-        }),
+        choices,
+        usage: Some(usage),
     }))
 }
 
-fn build_triton_request(
-    request: ChatCompletionCreateParams,
-    history_builder: &HistoryBuilder,
-) -> anyhow::Result<ModelInferRequest> {
-    let chat_history = history_builder.build_history(&request.messages)?;
-    tracing::debug!("chat history after formatting: {}", chat_history);
+/// The choices of a non-streaming response, whose message is checked when JSON output was
+/// requested.
+fn completion_choices(
+    choices: &[ChoiceState],
+    outputs: Vec<Vec<ParsedOutput>>,
+    json_output_validator: Option<&JsonOutputValidator>,
+    token_counter: &TokenCounter,
+) -> Result<Vec<ChatCompletionChoice>, AppError> {
+    choices
+        .iter()
+        .zip(outputs)
+        .enumerate()
+        .map(|(index, (choice, outputs))| {
+            let message = message(outputs);
+            let finish_reason = choice.finish_reason(token_counter);
+            // A truncated message can't be complete JSON, `finish_reason` tells the client why.
+            if let (Some(validator), Some(content), FinishReason::Stop) =
+                (json_output_validator, &message.content, &finish_reason)
+            {
+                validator.validate(content)?;
+            }
+            Ok(ChatCompletionChoice {
+                index,
+                message,
+                logprobs: choice
+                    .top_logprobs
+                    .map(|_| ChoiceLogprobs::new(&choice.logprobs)),
+                finish_reason: Some(finish_reason),
+            })
+        })
+        .collect()
+}
 
-    let mut sampling_parameters = request.sampling_parameters.clone();
+/// The progress of a single choice, fed with the responses of its own Triton request.
+struct ChoiceState {
+    stop_matcher: StopMatcher,
+    tool_call_parser: Option<ToolCallParser>,
+    /// Number of tool calls parsed from the output.
+    tool_call_count: usize,
+    /// Number of tool calls already sent to the client, which numbers the next one.
+    sent_tool_calls: usize,
+    usage_tracker: UsageTracker,
+    finish_reason_tracker: FinishReasonTracker,
+    text: String,
+    max_tokens: usize,
+    /// Number of most likely tokens whose log probabilities are returned, when requested.
+    top_logprobs: Option<usize>,
+    /// Log probabilities of the generated tokens.
+    logprobs: Vec<TokenLogprob>,
+    /// Number of `logprobs` already sent to the client.
+    sent_logprobs: usize,
+}
 
-    // Overwrite the max_tokens field with request.max_tokens
-    if let Some(obj) = sampling_parameters.as_object_mut() {
-        obj.insert("max_tokens".to_string(), serde_json::Value::Number(request.max_tokens.into()));
-        obj.insert("temperature".to_string(), serde_json::Value::Number(
-                serde_json::Number::from_f64(request.temperature as f64).unwrap()
-            ));
-        obj.insert("top_p".to_string(), serde_json::Value::Number(
-                serde_json::Number::from_f64(request.top_p as f64).unwrap()
-            ));
+impl ChoiceState {
+    fn new(
+        request: &ChatCompletionCreateParams,
+        state: &AppState,
+        sampling_params: &SamplingParams,
+    ) -> Self {
+        Self {
+            stop_matcher: StopMatcher::new(&request.stop),
+            tool_call_parser: tool_call_parser(request, state),
+            tool_call_count: 0,
+            sent_tool_calls: 0,
+            usage_tracker: UsageTracker::default(),
+            finish_reason_tracker: FinishReasonTracker::default(),
+            text: String::new(),
+            max_tokens: sampling_params
+                .max_tokens
+                .unwrap_or_else(default_max_tokens),
+            top_logprobs: request.logprobs.then(|| request.top_logprobs.unwrap_or(0)),
+            logprobs: Vec::new(),
+            sent_logprobs: 0,
+        }
+    }
+
+    /// Consume the next response and return the output that can be sent to the client.
+    fn push(&mut self, response: &ModelInferResponse) -> anyhow::Result<Vec<ParsedOutput>> {
+        self.usage_tracker.record(response);
+        self.finish_reason_tracker.record(response)?;
+        let content = string_output(response, "text_output")?.unwrap_or_default();
+        tracing::debug!("deserialized triton infer response content: {:?}", content);
+        self.text.push_str(&content);
+        if let Some(top_logprobs) = self.top_logprobs {
+            self.logprobs
+                .extend(logprobs_output(response, top_logprobs)?);
+        }
+
+        let content = self.stop_matcher.push(&content);
+        Ok(self.parse(&content))
+    }
+
+    /// Whether the choice generated one of the stop sequences.
+    fn is_stopped(&self) -> bool {
+        self.stop_matcher.is_stopped()
+    }
+
+    /// Flush the output held back by the stop matcher and the tool call parser.
+    fn finish(&mut self) -> Vec<ParsedOutput> {
+        let content = self.stop_matcher.finish();
+        let mut outputs = self.parse(&content);
+        if let Some(parser) = self.tool_call_parser.as_mut() {
+            let flushed = parser.finish();
+            self.count_tool_calls(&flushed);
+            outputs.extend(flushed);
+        }
+        outputs
+    }
+
+    fn parse(&mut self, content: &str) -> Vec<ParsedOutput> {
+        let outputs = match self.tool_call_parser.as_mut() {
+            Some(parser) => parser.push(content),
+            None if content.is_empty() => vec![],
+            None => vec![ParsedOutput::Content(content.to_string())],
+        };
+        self.count_tool_calls(&outputs);
+        outputs
+    }
+
+    fn count_tool_calls(&mut self, outputs: &[ParsedOutput]) {
+        self.tool_call_count += outputs
+            .iter()
+            .filter(|output| matches!(output, ParsedOutput::ToolCall(_)))
+            .count();
+    }
+
+    /// The log probabilities of the tokens generated since the previous chunk, when requested.
+    fn take_logprobs(&mut self) -> Option<ChoiceLogprobs> {
+        self.top_logprobs?;
+        let logprobs = ChoiceLogprobs::new(&self.logprobs[self.sent_logprobs..]);
+        self.sent_logprobs = self.logprobs.len();
+        Some(logprobs)
+    }
+
+    /// Convert a piece of output into a chunk delta, numbering the tool calls.
+    fn delta(&mut self, output: ParsedOutput) -> ChatCompletionChunkDelta {
+        match output {
+            ParsedOutput::Content(content) => ChatCompletionChunkDelta {
+                role: Some(Role::Assistant),
+                content: Some(content),
+                tool_calls: None,
+            },
+            ParsedOutput::ToolCall(call) => {
+                let index = self.sent_tool_calls;
+                self.sent_tool_calls += 1;
+                ChatCompletionChunkDelta {
+                    role: Some(Role::Assistant),
+                    content: None,
+                    tool_calls: Some(vec![ChatCompletionChunkToolCall {
+                        index,
+                        id: call.id,
+                        kind: ToolType::Function,
+                        function: FunctionCall {
+                            name: call.name,
+                            arguments: call.arguments,
+                        },
+                    }]),
+                }
+            }
+        }
+    }
+
+    fn finish_reason(&self, token_counter: &TokenCounter) -> FinishReason {
+        if self.tool_call_count > 0 {
+            return FinishReason::ToolCalls;
+        }
+        if self.is_stopped() {
+            return FinishReason::Stop;
+        }
+        let completion_tokens = self
+            .usage_tracker
+            .completion_tokens(token_counter, &self.text);
+        if self
+            .finish_reason_tracker
+            .is_length(completion_tokens, self.max_tokens)
+        {
+            FinishReason::Length
+        } else {
+            FinishReason::Stop
+        }
+    }
+}
+
+/// Sum up the usage of all choices, which share the same prompt.
+fn total_usage(choices: &[ChoiceState], token_counter: &TokenCounter, prompt: &str) -> Usage {
+    let mut usage = Usage::default();
+    for choice in choices {
+        let (prompt_tokens, completion_tokens) =
+            choice
+                .usage_tracker
+                .finish(token_counter, prompt, &choice.text);
+        usage.prompt_tokens = prompt_tokens;
+        usage.completion_tokens += completion_tokens;
+    }
+    usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+    usage
+}
+
+/// Number of tokens generated for all the choices, if known.
+fn completion_tokens(choices: &[ChoiceState], token_counter: &TokenCounter) -> Option<usize> {
+    choices
+        .iter()
+        .map(|choice| {
+            choice
+                .usage_tracker
+                .completion_tokens(token_counter, &choice.text)
+        })
+        .sum()
+}
+
+/// Create a tool call parser if the request allows the model to call any tools.
+fn tool_call_parser(
+    request: &ChatCompletionCreateParams,
+    state: &AppState,
+) -> Option<ToolCallParser> {
+    let has_tools = request
+        .tools
+        .as_ref()
+        .is_some_and(|tools| !tools.is_empty());
+    let disabled = matches!(
+        request.tool_choice,
+        Some(ChatCompletionToolChoice::Mode(ToolChoiceMode::None))
+    );
+    (has_tools && !disabled).then(|| ToolCallParser::new(state.tool_call_format.clone()))
+}
+
+/// Assemble the message of a choice from its parsed output.
+fn message(outputs: Vec<ParsedOutput>) -> ChatCompletionMessage {
+    let mut content = String::new();
+    let mut tool_calls = Vec::new();
+    for output in outputs {
+        match output {
+            ParsedOutput::Content(text) => content.push_str(&text),
+            ParsedOutput::ToolCall(call) => tool_calls.push(ChatCompletionMessageToolCall {
+                id: call.id,
+                kind: ToolType::Function,
+                function: FunctionCall {
+                    name: call.name,
+                    arguments: call.arguments,
+                },
+            }),
+        }
+    }
+
+    let (content, tool_calls) = if tool_calls.is_empty() {
+        (Some(content), None)
+    } else if content.trim().is_empty() {
+        (None, Some(tool_calls))
+    } else {
+        (Some(content), Some(tool_calls))
+    };
+    ChatCompletionMessage {
+        role: Role::Assistant,
+        content,
+        tool_calls,
+    }
+}
+
+/// Create a validator for the JSON output requested through `response_format`, if any.
+fn json_output_validator(
+    request: &ChatCompletionCreateParams,
+) -> anyhow::Result<Option<JsonOutputValidator>> {
+    match &request.response_format {
+        None | Some(ResponseFormat::Text) => Ok(None),
+        Some(ResponseFormat::JsonObject) => Ok(Some(JsonOutputValidator::new(None)?)),
+        Some(ResponseFormat::JsonSchema { json_schema }) => {
+            Ok(Some(JsonOutputValidator::new(json_schema.schema.as_ref())?))
+        }
     }
+}
 
-    // Serialize the modified sampling_parameters back to a JSON string
-    let modified_sampling_parameters_data = serde_json::to_string(&sampling_parameters)?;
+/// Render the message history, along with the available tools, into the prompt.
+fn build_prompt(
+    request: &ChatCompletionCreateParams,
+    model: &ModelRoute,
+) -> anyhow::Result<String> {
+    let tools = match request.tool_choice {
+        Some(ChatCompletionToolChoice::Mode(ToolChoiceMode::None)) => None,
+        _ => request.tools.as_deref(),
+    };
+    let chat_history = model.history_builder.build_history_with_tools(
+        &request.messages,
+        tools,
+        request.tool_choice.as_ref(),
+    )?;
+    tracing::debug!("chat history after formatting: {}", chat_history);
+    Ok(chat_history)
+}
 
-    // Convert the JSON string to bytes
-    let sampling_parameters_data = modified_sampling_parameters_data.as_bytes().to_vec();
+/// Build one triton request per requested choice. When a seed is given, every choice gets its own
+/// seed derived from it so that the choices differ but stay reproducible.
+fn build_triton_requests(
+    request: &ChatCompletionCreateParams,
+    model: &ModelRoute,
+    chat_history: &str,
+    sampling_params: &SamplingParams,
+    vllm_additional_outputs: bool,
+    max_n: usize,
+) -> anyhow::Result<Vec<ModelInferRequest>> {
+    if model.backend != Backend::Vllm {
+        anyhow::bail!(InvalidRequest::param(
+            "model",
+            format!(
+                "model '{}' does not support chat completions, only models of the vllm backend do",
+                request.model
+            )
+        ));
+    }
+    if request.n == 0 {
+        anyhow::bail!(InvalidRequest::param("n", "n must be at least 1"));
+    }
+    if request.n > max_n {
+        anyhow::bail!(InvalidRequest::param(
+            "n",
+            format!("n must be less than or equal to {}", max_n)
+        ));
+    }
+    (0..request.n)
+        .map(|i| {
+            let mut sampling_params = sampling_params.clone();
+            if let Some(seed) = request.seed {
+                sampling_params.seed = Some(seed.wrapping_add(i) as u64);
+            }
+            build_triton_request(
+                request,
+                model,
+                chat_history,
+                &sampling_params,
+                vllm_additional_outputs,
+            )
+        })
+        .collect()
+}
+
+fn build_triton_request(
+    request: &ChatCompletionCreateParams,
+    model: &ModelRoute,
+    chat_history: &str,
+    sampling_params: &SamplingParams,
+    vllm_additional_outputs: bool,
+) -> anyhow::Result<ModelInferRequest> {
+    let sampling_parameters = serde_json::to_string(sampling_params)?;
 
     let mut builder = Builder::new()
-        .model_name(request.model)
+        .model_name(model.triton_model.clone())
+        .model_version(model.triton_model_version.clone())
         .input(
             "text_input",
             [1],
@@ -248,26 +707,94 @@ fn build_triton_request(
         .input(
             "sampling_parameters",
             [1],
-            InferTensorData::Bytes(vec![sampling_parameters_data]),
-        )
-        .input(
-            "stream",
-            [1],
-            InferTensorData::Bool(vec![request.stream]),
+            InferTensorData::Bytes(vec![sampling_parameters.into_bytes()]),
         )
+        .input("stream", [1], InferTensorData::Bool(vec![request.stream]))
         .output("text_output");
 
-    if request.seed.is_some() {
-        builder = builder.input(
-            "random_seed",
-            [1, 1],
-            InferTensorData::UInt64(vec![request.seed.unwrap() as u64]),
-        );
+    if vllm_additional_outputs {
+        builder = additional_outputs(builder);
+    }
+    if request.logprobs {
+        builder = request_logprobs(builder);
     }
 
     builder.build().context("failed to build triton request")
 }
 
+/// Map the OpenAI request fields onto the vLLM sampling parameters, on top of whatever was given
+/// through `sampling_parameters` and the defaults of the model.
+fn sampling_params(
+    request: &ChatCompletionCreateParams,
+    model: &ModelRoute,
+) -> anyhow::Result<SamplingParams> {
+    let mut parameters = model.sampling_parameters.clone();
+    parameters.extend(request.sampling_parameters.clone());
+    let mut params = SamplingParams::from_value(serde_json::Value::Object(parameters))?;
+    params.max_tokens = request
+        .max_tokens
+        .or(params.max_tokens)
+        .or_else(|| Some(default_max_tokens()));
+    params.temperature = request.temperature.or(params.temperature);
+    params.top_p = request.top_p.or(params.top_p);
+    params.frequency_penalty = request.frequency_penalty.or(params.frequency_penalty);
+    params.presence_penalty = request.presence_penalty.or(params.presence_penalty);
+    if !request.stop.is_empty() {
+        params.stop = Some(request.stop.clone());
+    }
+    if let Some(logit_bias) = &request.logit_bias {
+        params.set_logit_bias(logit_bias)?;
+    }
+    params.top_k = request.top_k.or(params.top_k);
+    params.min_p = request.min_p.or(params.min_p);
+    params.repetition_penalty = request.repetition_penalty.or(params.repetition_penalty);
+    params.min_tokens = request.min_tokens.or(params.min_tokens);
+    params.ignore_eos = request.ignore_eos.or(params.ignore_eos);
+    if let Some(top_logprobs) = request.top_logprobs {
+        if !request.logprobs {
+            anyhow::bail!(InvalidRequest::param(
+                "top_logprobs",
+                "top_logprobs is only allowed when logprobs is true"
+            ));
+        }
+        if top_logprobs > MAX_TOP_LOGPROBS {
+            anyhow::bail!(InvalidRequest::param(
+                "top_logprobs",
+                format!(
+                    "top_logprobs must be at most {}, got {}",
+                    MAX_TOP_LOGPROBS, top_logprobs
+                )
+            ));
+        }
+    }
+    if request.logprobs {
+        params.logprobs = Some(request.top_logprobs.unwrap_or(0));
+    }
+
+    // Constrain the output with the guided decoding of vLLM.
+    match &request.response_format {
+        None | Some(ResponseFormat::Text) => {}
+        Some(ResponseFormat::JsonObject)
+        | Some(ResponseFormat::JsonSchema {
+            json_schema: JsonSchemaFormat { schema: None, .. },
+        }) => {
+            params.guided_decoding = Some(json!({ "json_object": true }));
+        }
+        Some(ResponseFormat::JsonSchema {
+            json_schema:
+                JsonSchemaFormat {
+                    schema: Some(schema),
+                    ..
+                },
+        }) => {
+            params.guided_decoding = Some(json!({ "json": schema }));
+        }
+    }
+
+    params.validate()?;
+    Ok(params)
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub(crate) struct ChatCompletionCreateParams {
@@ -278,51 +805,72 @@ pub(crate) struct ChatCompletionCreateParams {
     /// Number between -2.0 and 2.0. Positive values penalize new tokens based on their existing
     /// frequency in the text so far, decreasing the model's likelihood to repeat the same line
     /// verbatim.
-    #[serde(default = "default_frequency_penalty")]
-    frequency_penalty: f32,
+    frequency_penalty: Option<f32>,
     /// Modify the likelihood of specified tokens appearing in the completion.
     logit_bias: Option<HashMap<String, f32>>,
+    /// Whether to return the log probabilities of the output tokens.
+    #[serde(default)]
+    logprobs: bool,
+    /// The number of most likely tokens to return at each token position, between 0 and 20,
+    /// with their log probability. logprobs must be set to true if this parameter is used.
+    top_logprobs: Option<usize>,
     /// The maximum number of tokens to generate in the completion.
-    #[serde(default = "default_max_tokens")]
-    max_tokens: usize,
+    max_tokens: Option<usize>,
     /// How many completions to generate for each prompt.
     #[serde(default = "default_n")]
     n: usize,
     /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they
     /// appear in the text so far, increasing the model's likelihood to talk about new topics.
-    #[serde(default = "default_presence_penalty")]
-    presence_penalty: f32,
+    presence_penalty: Option<f32>,
     /// An object specifying the format that the model must output.
     /// Setting to { "type": "json_object" } enables JSON mode, which guarantees the message the
-    /// model generates is valid JSON.
+    /// model generates is valid JSON. Setting to { "type": "json_schema", "json_schema": {...} }
+    /// enables Structured Outputs which ensures the model will match the supplied JSON schema.
     response_format: Option<ResponseFormat>,
     /// If specified, our system will make a best effort to sample deterministically, such that
     /// repeated requests with the same seed and parameters should return the same result.
     seed: Option<usize>,
     /// Up to 4 sequences where the API will stop generating further tokens. The returned text will
     /// not contain the stop sequence.
-    stop: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "string_or_seq_string")]
+    stop: Vec<String>,
     /// Whether to stream back partial progress.
     #[serde(default = "default_stream")]
     stream: bool,
     /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make the
     /// output more random, while lower values like 0.2 will make it more focused and deterministic.
-    #[serde(default = "default_temperature")]
-    temperature: f32,
+    temperature: Option<f32>,
     /// An alternative to sampling with temperature, called nucleus sampling, where the model
     /// considers the results of the tokens with top_p probability mass. So 0.1 means only the
     /// tokens comprising the top 10% probability mass are considered.
-    #[serde(default = "default_top_p")]
-    top_p: f32,
+    top_p: Option<f32>,
     /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect
     /// abuse.
     user: Option<String>,
-    // Not supported yet:
-    // tools
-    // tool_choices
+    /// A list of tools the model may call. Currently, only functions are supported as a tool.
+    tools: Option<Vec<ChatCompletionTool>>,
+    /// Controls which (if any) function is called by the model.
+    tool_choice: Option<ChatCompletionToolChoice>,
+    /// Options for streaming response. Only set this when you set `stream: true`.
+    stream_options: Option<StreamOptions>,
+    /// Only sample from the `top_k` most likely tokens, -1 to consider all tokens. Not part of the
+    /// OpenAI API.
+    top_k: Option<i32>,
+    /// Minimum probability of a token, relative to the most likely one, to be considered. Not part
+    /// of the OpenAI API.
+    min_p: Option<f32>,
+    /// Values greater than 1.0 penalize tokens that already appeared in the prompt or the
+    /// generated text. Not part of the OpenAI API.
+    repetition_penalty: Option<f32>,
+    /// The minimum number of tokens to generate before the end of sequence token or a stop
+    /// sequence may end the completion. Not part of the OpenAI API.
+    min_tokens: Option<usize>,
+    /// Keep generating after the end of sequence token. Not part of the OpenAI API.
+    ignore_eos: Option<bool>,
 
-    #[serde(default = "default_sampling_parameters")]
-    sampling_parameters: serde_json::Value,
+    /// Extra vLLM sampling parameters. Not part of the OpenAI API.
+    #[serde(default)]
+    sampling_parameters: serde_json::Map<String, serde_json::Value>,
 }
 
 #[allow(dead_code)]
@@ -338,9 +886,8 @@ pub enum ChatCompletionMessageParams {
         name: Option<String>,
     },
     Assistant {
-        content: String,
-        // Not supported yet:
-        // tool_calls
+        content: Option<String>,
+        tool_calls: Option<Vec<ChatCompletionMessageToolCall>>,
     },
     Tool {
         content: String,
@@ -348,12 +895,101 @@ pub enum ChatCompletionMessageParams {
     },
 }
 
-#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolType {
+    Function,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatCompletionTool {
+    /// The type of the tool. Currently, only function is supported.
+    #[serde(rename = "type")]
+    pub kind: ToolType,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionDefinition {
+    /// The name of the function to be called.
+    pub name: String,
+    /// A description of what the function does, used by the model to choose when and how to call
+    /// the function.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The parameters the functions accepts, described as a JSON Schema object.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ChatCompletionToolChoice {
+    /// `none` means the model will not call a function, `auto` means the model can pick between
+    /// generating a message or calling a function and `required` means the model must call one.
+    Mode(ToolChoiceMode),
+    /// Forces the model to call the named function.
+    Named(ChatCompletionNamedToolChoice),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    None,
+    Auto,
+    Required,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatCompletionNamedToolChoice {
+    #[serde(rename = "type")]
+    pub kind: ToolType,
+    pub function: FunctionName,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatCompletionMessageToolCall {
+    /// The ID of the tool call.
+    pub id: String,
+    /// The type of the tool. Currently, only function is supported.
+    #[serde(rename = "type")]
+    pub kind: ToolType,
+    /// The function that the model called.
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionCall {
+    /// The name of the function to call.
+    pub name: String,
+    /// The arguments to call the function with, as generated by the model in JSON format.
+    pub arguments: String,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ResponseFormat {
     Text,
     JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Debug)]
+struct JsonSchemaFormat {
+    /// The name of the response format.
+    name: String,
+    /// A description of what the response format is for.
+    description: Option<String>,
+    /// The schema for the response format, described as a JSON Schema object.
+    schema: Option<serde_json::Value>,
+    /// Whether to enable strict schema adherence when generating the output.
+    strict: Option<bool>,
 }
 
 #[derive(Serialize, Debug)]
@@ -378,17 +1014,71 @@ struct ChatCompletion {
 struct ChatCompletionChoice {
     index: usize,
     message: ChatCompletionMessage,
+    /// Log probability information for the choice.
+    logprobs: Option<ChoiceLogprobs>,
     finish_reason: Option<FinishReason>,
 }
 
+#[derive(Serialize, Debug)]
+struct ChoiceLogprobs {
+    /// The log probabilities of the message content tokens.
+    content: Vec<ChatCompletionTokenLogprob>,
+}
+
+impl ChoiceLogprobs {
+    fn new(logprobs: &[TokenLogprob]) -> Self {
+        let content = logprobs
+            .iter()
+            .map(|logprob| ChatCompletionTokenLogprob {
+                token: logprob.token.clone(),
+                logprob: logprob.logprob,
+                bytes: logprob.token.as_bytes().to_vec(),
+                top_logprobs: logprob
+                    .top_logprobs
+                    .iter()
+                    .map(|(token, logprob)| TopLogprob {
+                        token: token.clone(),
+                        logprob: *logprob,
+                        bytes: token.as_bytes().to_vec(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Self { content }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ChatCompletionTokenLogprob {
+    /// The token.
+    token: String,
+    /// The log probability of this token.
+    logprob: f32,
+    /// The UTF-8 bytes representation of the token.
+    bytes: Vec<u8>,
+    /// The most likely tokens at this token position, with their log probability.
+    top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Serialize, Debug)]
+struct TopLogprob {
+    /// The token.
+    token: String,
+    /// The log probability of this token.
+    logprob: f32,
+    /// The UTF-8 bytes representation of the token.
+    bytes: Vec<u8>,
+}
+
 #[derive(Serialize, Debug)]
 struct ChatCompletionMessage {
     /// The role of the author of this message.
     role: Role,
     /// The contents of the chunk message.
     content: Option<String>,
-    // Not supported yet:
-    // tool_calls
+    /// The tool calls generated by the model, such as function calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ChatCompletionMessageToolCall>>,
 }
 
 #[allow(dead_code)]
@@ -430,12 +1120,18 @@ struct ChatCompletionChunk {
     system_fingerprint: Option<String>,
     /// A list of chat completion choices. Can be more than one if n is greater than 1.
     choices: Vec<ChatCompletionChunkChoice>,
+    /// Usage statistics for the entire request, only present when `stream_options.include_usage`
+    /// is set, and null but in the last chunk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Option<Usage>>,
 }
 
 #[derive(Serialize, Debug)]
 struct ChatCompletionChunkChoice {
     index: usize,
     delta: ChatCompletionChunkDelta,
+    /// Log probability information for the tokens of the chunk.
+    logprobs: Option<ChoiceLogprobs>,
     finish_reason: Option<FinishReason>,
 }
 
@@ -447,8 +1143,18 @@ struct ChatCompletionChunkDelta {
     /// The contents of the chunk message.
     #[serde(skip_serializing_if = "Option::is_none")]
     content: Option<String>,
-    // Not supported yet:
-    // tool_calls
+    /// The tool calls generated by the model, such as function calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ChatCompletionChunkToolCall>>,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatCompletionChunkToolCall {
+    index: usize,
+    id: String,
+    #[serde(rename = "type")]
+    kind: ToolType,
+    function: FunctionCall,
 }
 
 #[allow(dead_code)]
@@ -461,10 +1167,6 @@ enum Role {
     Tool,
 }
 
-fn default_frequency_penalty() -> f32 {
-    0.0
-}
-
 fn default_max_tokens() -> usize {
     16
 }
@@ -473,26 +1175,142 @@ fn default_n() -> usize {
     1
 }
 
-fn default_presence_penalty() -> f32 {
-    0.0
-}
-
 fn default_stream() -> bool {
     false
 }
 
-fn default_temperature() -> f32 {
-    1.0
-}
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
 
-fn default_top_p() -> f32 {
-    1.0
-}
+    use axum::body::to_bytes;
+    use axum::http::StatusCode;
+    use clap::Parser;
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::history::HistoryBuilder;
+    use crate::registry::ModelRegistry;
+    use crate::tool_calls::ToolCallFormat;
+    use crate::triton::pool::EndpointPool;
 
-fn default_sampling_parameters() -> serde_json::Value {
-    serde_json::json!({
-        "temperature": 0.1,
-        "top_p": 0.95,
-        "max_tokens": 100,
-    })
-}
\ No newline at end of file
+    fn route(args: &[&str]) -> ModelRoute {
+        let config = Config::parse_from(["openai_trtllm"].iter().chain(args));
+        let pool = EndpointPool::new(
+            &config.triton_endpoint,
+            config.load_balancing,
+            Duration::from_secs(config.ejection_secs),
+        )
+        .unwrap();
+        let history_builder = HistoryBuilder::new(&None, &None).unwrap();
+        ModelRegistry::new(&config, pool, history_builder)
+            .unwrap()
+            .resolve("model")
+    }
+
+    #[tokio::test]
+    pub async fn test_tensorrt_llm_backend() {
+        let model = route(&["--backend", "tensorrt-llm"]);
+        let request: ChatCompletionCreateParams = serde_json::from_value(json!({
+            "model": "model",
+            "messages": [{"role": "user", "content": "test"}],
+        }))
+        .unwrap();
+        let sampling_params = sampling_params(&request, &model).unwrap();
+
+        let error = build_triton_requests(&request, &model, "test", &sampling_params, false, 128)
+            .unwrap_err();
+        let error = AppError::from(error);
+        assert_eq!("model", error.body()["error"]["param"]);
+        assert_eq!(StatusCode::BAD_REQUEST, error.into_response().status());
+    }
+
+    #[tokio::test]
+    pub async fn test_max_n() {
+        let model = route(&[]);
+
+        let requests = |n: usize| {
+            let request: ChatCompletionCreateParams = serde_json::from_value(json!({
+                "model": "model",
+                "messages": [{"role": "user", "content": "test"}],
+                "n": n,
+            }))
+            .unwrap();
+            let sampling_params = sampling_params(&request, &model).unwrap();
+            build_triton_requests(&request, &model, "test", &sampling_params, false, 2)
+        };
+        assert_eq!(2, requests(2).unwrap().len());
+        let error = requests(3).err().unwrap();
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            AppError::from(error).into_response().status()
+        );
+    }
+
+    fn choice(tool_call_parser: Option<ToolCallParser>) -> ChoiceState {
+        ChoiceState {
+            stop_matcher: StopMatcher::new(&[]),
+            tool_call_parser,
+            tool_call_count: 0,
+            sent_tool_calls: 0,
+            usage_tracker: UsageTracker::default(),
+            finish_reason_tracker: FinishReasonTracker::default(),
+            text: String::new(),
+            max_tokens: default_max_tokens(),
+            top_logprobs: None,
+            logprobs: Vec::new(),
+            sent_logprobs: 0,
+        }
+    }
+
+    #[test]
+    pub fn test_tool_calls_finish_reason() {
+        let mut choice = choice(Some(ToolCallParser::new(ToolCallFormat::default())));
+        let text = r#"<tool_call>{"name": "get_weather", "arguments": {"city": "Paris"}}"#;
+        choice.text = text.to_string();
+        let mut outputs = choice.parse(text);
+        outputs.extend(choice.finish());
+
+        let choices =
+            completion_choices(&[choice], vec![outputs], None, &TokenCounter::default()).unwrap();
+        let choice = serde_json::to_value(&choices[0]).unwrap();
+        assert_eq!("tool_calls", choice["finish_reason"]);
+        assert_eq!(
+            "get_weather",
+            choice["message"]["tool_calls"][0]["function"]["name"]
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_invalid_json_output() {
+        let mut choice = choice(None);
+        choice.text = "[1, 2]".to_string();
+        let outputs = vec![vec![ParsedOutput::Content("[1, 2]".to_string())]];
+        let validator = JsonOutputValidator::new(None).unwrap();
+
+        let response = completion_choices(
+            &[choice],
+            outputs,
+            Some(&validator),
+            &TokenCounter::default(),
+        )
+        .unwrap_err()
+        .into_response();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        let body: serde_json::Value =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap())
+                .unwrap();
+        assert_eq!(
+            json!({
+                "error": {
+                    "message": "model output is not a JSON object",
+                    "type": "server_error",
+                    "param": null,
+                    "code": "invalid_model_output",
+                }
+            }),
+            body
+        );
+    }
+}
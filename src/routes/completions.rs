@@ -4,82 +4,214 @@ use std::iter::IntoIterator;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
-use async_stream::{stream, try_stream};
+use async_stream::try_stream;
 use axum::extract::State;
 use axum::http::HeaderMap;
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::Json;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use axum::{Extension, Json};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use tonic::codegen::tokio_stream::Stream;
-use tonic::transport::Channel;
 use tracing;
 use tracing::instrument;
 use uuid::Uuid;
 
-use crate::error::AppError;
+use crate::admission::Permit;
+use crate::auth::{authorize_model, Identity};
+use crate::config::Backend;
+use crate::error::{AppError, AppJson, InvalidRequest};
+use crate::finish_reason::FinishReasonTracker;
+use crate::logprobs::{logprobs_output, request_logprobs, TokenLogprob};
+use crate::metrics::{GenerationMetrics, RequestModel};
+use crate::rate_limit::Quota;
+use crate::registry::ModelRoute;
+use crate::sampling::SamplingParams;
 use crate::state::AppState;
-use crate::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
+use crate::stream_options::{include_usage, StreamOptions};
 use crate::triton::request::{Builder, InferTensorData};
-use crate::triton::telemetry::propagate_context;
-use crate::triton::ModelInferRequest;
-use crate::utils::{deserialize_bytes_tensor, string_or_seq_string};
+use crate::triton::response::{f32_output, string_output};
+use crate::triton::streams::{model_stream_infer_all, Deadline};
+use crate::triton::{ModelInferRequest, ModelInferResponse};
+use crate::usage::{additional_outputs, TokenCounter, UsageTracker};
 
-#[instrument(name = "completions", skip(grpc_client, request))]
+/// Route of the endpoint, as the label of its metrics.
+const ROUTE: &str = "/v1/completions";
+
+/// Output of the vLLM backend with the log probability of the generated text.
+const CUMULATIVE_LOGPROB: &str = "cumulative_logprob";
+
+#[instrument(name = "completions", skip(state, identity, quota, request))]
 pub(crate) async fn compat_completions(
     headers: HeaderMap,
-    State(AppState { grpc_client, .. }): State<AppState>,
-    request: Json<CompletionCreateParams>,
+    State(state): State<AppState>,
+    identity: Option<Extension<Identity>>,
+    quota: Option<Extension<Quota>>,
+    AppJson(request): AppJson<CompletionCreateParams>,
 ) -> Response {
     tracing::info!("request: {:?}", request);
 
-    if request.stream {
-        completions_stream(headers, grpc_client, request)
-            .await
-            .into_response()
-    } else {
-        completions(headers, grpc_client, request)
+    let quota = quota.map(|Extension(quota)| quota);
+    let label = state.models.label(&request.model).to_string();
+    let model = RequestModel(label.clone());
+    let mut response = match admit(&state, &identity, &request, &label).await {
+        Err(e) => e.into_response(),
+        Ok((inference, permit)) if request.stream => {
+            completions_stream(headers, state, quota, permit, request, inference)
+                .await
+                .into_response()
+        }
+        Ok((inference, permit)) => completions(headers, state, quota, permit, request, inference)
             .await
-            .into_response()
+            .into_response(),
+    };
+    response.extensions_mut().insert(model);
+    response
+}
+
+/// Check the request, then wait for its turn, so that invalid requests are rejected right away.
+async fn admit(
+    state: &AppState,
+    identity: &Option<Extension<Identity>>,
+    request: &CompletionCreateParams,
+    label: &str,
+) -> Result<(Inference, Permit), AppError> {
+    authorize_model(identity, &request.model)?;
+    let inference = Inference::new(request, state)?;
+    let permit = state.admission.admit(identity, label).await?;
+    Ok((inference, permit))
+}
+
+/// The inferences of a request, checked before the request is admitted.
+struct Inference {
+    model: ModelRoute,
+    max_tokens: usize,
+    prompts: Vec<Prompt>,
+    /// The Triton request of every candidate of every prompt.
+    requests: Vec<ModelInferRequest>,
+    include_usage: bool,
+    /// The prompts returned before the generated texts with `echo`.
+    echo: Option<Vec<String>>,
+}
+
+impl Inference {
+    fn new(request: &CompletionCreateParams, state: &AppState) -> anyhow::Result<Self> {
+        let include_usage = include_usage(request.stream, &request.stream_options)?;
+        let echo = echo_texts(request, &state.token_counter)?;
+        let model = state.models.resolve(&request.model);
+        let max_tokens = max_tokens(request, &model);
+        let prompts = build_prompts(request, &model, &state.token_counter)?;
+        let requests = build_triton_requests(
+            &model,
+            request,
+            &prompts,
+            state.vllm_additional_outputs,
+            state.max_n,
+        )?;
+        Ok(Self {
+            model,
+            max_tokens,
+            prompts,
+            requests,
+            include_usage,
+            echo,
+        })
     }
 }
 
-#[instrument(name = "streaming completions", skip(client, request))]
+#[instrument(
+    name = "streaming completions",
+    skip(state, quota, permit, request, inference)
+)]
 async fn completions_stream(
     headers: HeaderMap,
-    mut client: GrpcInferenceServiceClient<Channel>,
-    Json(request): Json<CompletionCreateParams>,
+    state: AppState,
+    quota: Option<Quota>,
+    permit: Permit,
+    request: CompletionCreateParams,
+    inference: Inference,
 ) -> Result<Sse<impl Stream<Item = anyhow::Result<Event>>>, AppError> {
+    let mut generation = GenerationMetrics::new(ROUTE, state.models.label(&request.model), true);
+    let deadline = Deadline::new(state.timeouts);
     let id = format!("cmpl-{}", Uuid::new_v4());
     let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
     let model_name = request.model.clone();
-    let request = build_triton_request(request)?;
-
-    let response_stream = try_stream! {
-        let request = stream! { yield request };
-        let mut request = tonic::Request::new(request);
+    let with_logprobs = request.logprobs.is_some();
+    let Inference {
+        model,
+        max_tokens,
+        prompts,
+        requests,
+        include_usage,
+        echo,
+    } = inference;
+    // Every candidate is returned when streaming, as the choice of the same index.
+    let per_prompt = requests.len() / prompts.len();
+    let mut candidates = new_candidates(&prompts, per_prompt, request.logprobs);
+    let triton_model_label = model.triton_model_label().to_string();
+    let pool = model.pool;
+    let token_counter = state.token_counter;
 
-        propagate_context(&mut request, &headers);
+    let chunk = move |choices: Vec<CompletionChoice>, usage: Option<Usage>| {
+        let response = Completion {
+            id: id.clone(),
+            object: "text_completion".to_string(),
+            created,
+            model: model_name.clone(),
+            choices,
+            // Every chunk but the last one has a null usage when it is included.
+            usage: include_usage.then_some(usage),
+        };
+        Event::default().json_data(response).unwrap()
+    };
 
-        let mut stream = client
-            .model_stream_infer(request)
-            .await
-            .context("failed to call triton grpc method model_stream_infer")?
-            .into_inner();
+    let response_stream = try_stream! {
+        // Held by the stream, the next request is let in once the client is done with it.
+        let _permit = permit;
+        let mut streams = model_stream_infer_all(&pool, &headers, requests, &triton_model_label, deadline);
 
-        while let Some(response) = stream.message().await? {
+        if let Some(echo) = &echo {
+            for index in 0..candidates.len() {
+                yield chunk(vec![CompletionChoice {
+                    text: echo[index / per_prompt].clone(),
+                    index,
+                    logprobs: with_logprobs.then(CompletionLogprobs::default),
+                    finish_reason: None,
+                }], None);
+            }
+        }
+        loop {
+            let (index, response) = match deadline.next(&mut streams).await {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                // The generation is cancelled as the responses are dropped.
+                Err(e) => {
+                    yield AppError::from(e).event();
+                    return;
+                }
+            };
+            let candidate = &mut candidates[index];
+            let Some(response) = response else {
+                yield chunk(vec![CompletionChoice {
+                    text: String::new(),
+                    index,
+                    logprobs: with_logprobs.then(CompletionLogprobs::default),
+                    finish_reason: Some(candidate.finish_reason(&token_counter, max_tokens)),
+                }], None);
+                continue;
+            };
+            // Also the errors of the inferences that failed to start.
+            let response = match response {
+                Ok(response) => response,
+                Err(status) => {
+                    yield AppError::from(status).event();
+                    return;
+                }
+            };
             if !response.error_message.is_empty() {
                 tracing::error!("received error message from triton: {}", response.error_message);
 
-                // Corresponds to https://github.com/openai/openai-python/blob/17ac6779958b2b74999c634c4ea4c7b74906027a/src/openai/_streaming.py#L113
-                yield Event::default().event("error").json_data(json!({
-                    "error": {
-                        "status_code": 500,
-                        "message": "Internal Server Error"
-                    }
-                })).unwrap();
+                yield AppError::from_triton_message(&response.error_message).event();
                 return;
             }
             let infer_response = response
@@ -87,43 +219,32 @@ async fn completions_stream(
                 .context("empty infer response received")?;
             tracing::debug!("triton infer response: {:?}", infer_response);
 
-            let raw_content = infer_response.raw_output_contents[0].clone();
-            let content = deserialize_bytes_tensor(raw_content)?
-                .into_iter()
-                .collect::<String>();
-            tracing::debug!("deserialized triton infer response content: {:?}", content);
-
-            if !content.is_empty() {
-                let response = Completion {
-                    id: id.clone(),
-                    object: "text_completion".to_string(),
-                    created,
-                    model: model_name.clone(),
-                    choices: vec![CompletionChoice {
-                        text: content,
-                        index: 0,
-                        logprobs: None,
-                        finish_reason: None,
-                    }],
-                    usage: None,
-                };
-                yield Event::default().json_data(response).unwrap();
+            // The offsets of the tokens count the characters of the echoed prompt and of the text
+            // generated so far.
+            let text_offset = echo.as_ref().map_or(0, |echo| echo[index / per_prompt].chars().count())
+                + candidate.text_chars;
+            let (content, logprobs) = candidate.push(&infer_response)?;
+            if !content.is_empty() || !logprobs.is_empty() {
+                generation.output();
+                yield chunk(vec![CompletionChoice {
+                    text: content,
+                    index,
+                    logprobs: with_logprobs.then(|| CompletionLogprobs::new(&logprobs, text_offset)),
+                    finish_reason: None,
+                }], None);
             }
         }
-        let response = Completion {
-            id,
-            object: "text_completion".to_string(),
-            created,
-            model: model_name,
-            choices: vec![CompletionChoice {
-                text: String::new(),
-                index: 0,
-                logprobs: None,
-                finish_reason: Some(FinishReason::Stop),
-            }],
-            usage: None,
-        };
-        yield Event::default().json_data(response).unwrap();
+        generation.finish(completion_tokens(&candidates, &token_counter));
+        let usage = total_usage(&candidates, &token_counter, &prompts);
+        if let Some(quota) = &quota {
+            quota.consume_tokens(usage.total_tokens);
+        }
+
+        if include_usage {
+            // The usage statistics for the entire request are sent in an extra chunk with an
+            // empty choices list.
+            yield chunk(vec![], Some(usage));
+        }
 
         // OpenAI stream response terminated by a data: [DONE] message.
         yield Event::default().data("[DONE]");
@@ -132,44 +253,83 @@ async fn completions_stream(
     Ok(Sse::new(response_stream).keep_alive(KeepAlive::default()))
 }
 
-#[instrument(name = "non-streaming completions", skip(client, request), err(Debug))]
+#[instrument(
+    name = "non-streaming completions",
+    skip(state, quota, permit, request, inference),
+    err(Debug)
+)]
 async fn completions(
     headers: HeaderMap,
-    mut client: GrpcInferenceServiceClient<Channel>,
-    Json(request): Json<CompletionCreateParams>,
+    state: AppState,
+    quota: Option<Quota>,
+    permit: Permit,
+    request: CompletionCreateParams,
+    inference: Inference,
 ) -> Result<Json<Completion>, AppError> {
+    let mut generation = GenerationMetrics::new(ROUTE, state.models.label(&request.model), false);
+    let deadline = Deadline::new(state.timeouts.unstreamed());
     let model_name = request.model.clone();
-    let request = build_triton_request(request)?;
-    let request = stream! { yield request };
-    let mut request = tonic::Request::new(request);
-
-    propagate_context(&mut request, &headers);
+    let Inference {
+        model,
+        max_tokens,
+        prompts,
+        requests,
+        echo,
+        ..
+    } = inference;
+    let per_prompt = requests.len() / prompts.len();
+    let mut candidates = new_candidates(&prompts, per_prompt, request.logprobs);
+    let mut streams = model_stream_infer_all(
+        &model.pool,
+        &headers,
+        requests,
+        model.triton_model_label(),
+        deadline,
+    );
 
-    let mut stream = client
-        .model_stream_infer(request)
-        .await
-        .context("failed to call triton grpc method model_stream_infer")?
-        .into_inner();
-
-    let mut contents: Vec<String> = Vec::new();
-    while let Some(response) = stream.message().await? {
+    while let Some((index, response)) = deadline.next(&mut streams).await? {
+        let Some(response) = response else {
+            continue;
+        };
+        let response = response?;
         if !response.error_message.is_empty() {
-            return Err(anyhow::anyhow!(
-                "error message received from triton: {}",
-                response.error_message
-            )
-            .into());
+            return Err(AppError::from_triton_message(&response.error_message));
         }
         let infer_response = response
             .infer_response
             .context("empty infer response received")?;
         tracing::debug!("triton infer response: {:?}", infer_response);
 
-        let raw_content = infer_response.raw_output_contents[0].clone();
-        let content = deserialize_bytes_tensor(raw_content)?.into_iter().collect();
-        tracing::debug!("deserialized triton infer response content: {:?}", content);
+        let (content, _) = candidates[index].push(&infer_response)?;
+        if !content.is_empty() {
+            generation.output();
+        }
+    }
+    drop(permit);
 
-        contents.push(content);
+    // Every candidate counts, including the ones that are not returned.
+    generation.finish(completion_tokens(&candidates, &state.token_counter));
+    let usage = total_usage(&candidates, &state.token_counter, &prompts);
+    if let Some(quota) = &quota {
+        quota.consume_tokens(usage.total_tokens);
+    }
+    // The choices of every prompt follow each other, at `prompt_index * n + i`.
+    let mut choices = Vec::with_capacity(prompts.len() * request.n);
+    let mut candidates = candidates.into_iter();
+    for prompt_index in 0..prompts.len() {
+        let candidates = candidates.by_ref().take(per_prompt).collect();
+        for candidate in best_candidates(candidates, request.n) {
+            let echo = echo.as_ref().map_or("", |echo| &echo[prompt_index]);
+            let logprobs = request
+                .logprobs
+                .map(|_| CompletionLogprobs::new(&candidate.logprobs, echo.chars().count()));
+            choices.push(CompletionChoice {
+                text: format!("{}{}", echo, candidate.text),
+                index: choices.len(),
+                logprobs,
+                finish_reason: Some(candidate.finish_reason(&state.token_counter, max_tokens)),
+            });
+        }
     }
 
     Ok(Json(Completion {
@@ -177,40 +337,511 @@ async fn completions(
         object: "text_completion".to_string(),
         created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
         model: model_name,
-        choices: vec![CompletionChoice {
-            text: contents.into_iter().collect(),
-            index: 0,
-            logprobs: None,
-            finish_reason: Some(FinishReason::Stop),
-        }],
-        // Not supported yet, need triton to return usage stats
-        // but add a fake one to make LangChain happy
-        usage: Some(Usage {
-            prompt_tokens: 0,
-            completion_tokens: 0,
-            total_tokens: 0,
-        }),
+        choices,
+        usage: Some(Some(usage)),
     }))
 }
 
-fn build_triton_request(request: CompletionCreateParams) -> anyhow::Result<ModelInferRequest> {
-    let mut builder = Builder::new()
-        .model_name(request.model)
+/// A prompt, as text or as token ids of the tokenizer of the model.
+#[derive(Clone, Debug, PartialEq)]
+enum Prompt {
+    Text(String),
+    Tokens(Vec<u32>),
+}
+
+impl Prompt {
+    /// The text of the prompt to count its tokens, empty for token ids whose count is known.
+    fn usage_text(&self) -> &str {
+        match self {
+            Prompt::Text(text) => text,
+            Prompt::Tokens(_) => "",
+        }
+    }
+}
+
+/// The forms of the `prompt` request field.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PromptParam {
+    Text(String),
+    Texts(Vec<String>),
+    Tokens(Vec<u32>),
+    TokenArrays(Vec<Vec<u32>>),
+}
+
+fn prompts<'de, D>(deserializer: D) -> Result<Vec<Prompt>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let prompt = PromptParam::deserialize(deserializer).map_err(|_| {
+        de::Error::custom(
+            "expected a string, an array of strings, an array of token ids or an array of arrays of token ids",
+        )
+    })?;
+    Ok(match prompt {
+        PromptParam::Text(text) => vec![Prompt::Text(text)],
+        PromptParam::Texts(texts) => texts.into_iter().map(Prompt::Text).collect(),
+        PromptParam::Tokens(tokens) => vec![Prompt::Tokens(tokens)],
+        PromptParam::TokenArrays(arrays) => arrays.into_iter().map(Prompt::Tokens).collect(),
+    })
+}
+
+/// The text of a prompt, token ids being decoded with the configured tokenizer.
+fn prompt_text(prompt: &Prompt, token_counter: &TokenCounter) -> anyhow::Result<String> {
+    match prompt {
+        Prompt::Text(text) => Ok(text.clone()),
+        Prompt::Tokens(tokens) => match token_counter.decode(tokens)? {
+            Some(text) => Ok(text),
+            None => anyhow::bail!(InvalidRequest::param(
+                "prompt",
+                "token ids are only supported by models taking input_ids, or with a tokenizer"
+            )),
+        },
+    }
+}
+
+/// The prompts returned before the generated texts with `echo`.
+fn echo_texts(
+    request: &CompletionCreateParams,
+    token_counter: &TokenCounter,
+) -> anyhow::Result<Option<Vec<String>>> {
+    if !request.echo {
+        return Ok(None);
+    }
+    let texts = request
+        .prompt
+        .iter()
+        .map(|prompt| match prompt {
+            Prompt::Text(text) => Ok(text.clone()),
+            Prompt::Tokens(tokens) => match token_counter.decode(tokens)? {
+                Some(text) => Ok(text),
+                None => anyhow::bail!(InvalidRequest::param(
+                    "echo",
+                    "echo is not supported for token prompts on this model"
+                )),
+            },
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Some(texts))
+}
+
+/// A completion generated for the request, returned to the client unless it is one of the
+/// `best_of` candidates that did not make the cut.
+#[derive(Default, Debug)]
+struct Candidate {
+    usage_tracker: UsageTracker,
+    finish_reason_tracker: FinishReasonTracker,
+    text: String,
+    /// Number of characters of `text`.
+    text_chars: usize,
+    /// Log probability of the generated text, only reported when candidates are ranked.
+    cumulative_logprob: Option<f32>,
+    /// Number of most likely tokens whose log probabilities are returned, when requested.
+    top_logprobs: Option<usize>,
+    /// Log probabilities of the generated tokens.
+    logprobs: Vec<TokenLogprob>,
+}
+
+impl Candidate {
+    fn new(prompt: &Prompt, top_logprobs: Option<usize>) -> Self {
+        let usage_tracker = match prompt {
+            Prompt::Text(_) => UsageTracker::default(),
+            Prompt::Tokens(tokens) => UsageTracker::with_prompt_tokens(tokens.len()),
+        };
+        Self {
+            usage_tracker,
+            top_logprobs,
+            ..Default::default()
+        }
+    }
+
+    /// Consume the next response and return the text it generated, with the log probabilities of
+    /// its tokens when requested.
+    fn push(
+        &mut self,
+        response: &ModelInferResponse,
+    ) -> anyhow::Result<(String, Vec<TokenLogprob>)> {
+        self.usage_tracker.record(response);
+        self.finish_reason_tracker.record(response)?;
+        if let Some(cumulative_logprob) = f32_output(response, CUMULATIVE_LOGPROB) {
+            self.cumulative_logprob = Some(cumulative_logprob);
+        }
+        let content = string_output(response, "text_output")?.unwrap_or_default();
+        tracing::debug!("deserialized triton infer response content: {:?}", content);
+        self.text.push_str(&content);
+        self.text_chars += content.chars().count();
+        let logprobs = match self.top_logprobs {
+            Some(top_logprobs) => logprobs_output(response, top_logprobs)?,
+            None => Vec::new(),
+        };
+        self.logprobs.extend_from_slice(&logprobs);
+        Ok((content, logprobs))
+    }
+
+    fn finish_reason(&self, token_counter: &TokenCounter, max_tokens: usize) -> FinishReason {
+        let completion_tokens = self
+            .usage_tracker
+            .completion_tokens(token_counter, &self.text);
+        if self
+            .finish_reason_tracker
+            .is_length(completion_tokens, max_tokens)
+        {
+            FinishReason::Length
+        } else {
+            FinishReason::Stop
+        }
+    }
+}
+
+/// The candidates of every prompt, following each other.
+fn new_candidates(
+    prompts: &[Prompt],
+    per_prompt: usize,
+    top_logprobs: Option<usize>,
+) -> Vec<Candidate> {
+    prompts
+        .iter()
+        .flat_map(|prompt| (0..per_prompt).map(move |_| Candidate::new(prompt, top_logprobs)))
+        .collect()
+}
+
+/// The `n` candidates with the highest log probability, in that order.
+fn best_candidates(mut candidates: Vec<Candidate>, n: usize) -> Vec<Candidate> {
+    if candidates.len() > n {
+        candidates.sort_by(|a, b| {
+            let logprob =
+                |candidate: &Candidate| candidate.cumulative_logprob.unwrap_or(f32::NEG_INFINITY);
+            logprob(b).total_cmp(&logprob(a))
+        });
+        candidates.truncate(n);
+    }
+    candidates
+}
+
+/// Usage of the whole request, the candidates of every prompt following each other.
+fn total_usage(
+    candidates: &[Candidate],
+    token_counter: &TokenCounter,
+    prompts: &[Prompt],
+) -> Usage {
+    let mut usage = Usage::default();
+    let per_prompt = candidates.len() / prompts.len();
+    for (prompt, candidates) in prompts.iter().zip(candidates.chunks(per_prompt)) {
+        let mut prompt_tokens = 0;
+        for candidate in candidates {
+            let (candidate_prompt_tokens, completion_tokens) =
+                candidate
+                    .usage_tracker
+                    .finish(token_counter, prompt.usage_text(), &candidate.text);
+            prompt_tokens = candidate_prompt_tokens;
+            usage.completion_tokens += completion_tokens;
+        }
+        usage.prompt_tokens += prompt_tokens;
+    }
+    usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+    usage
+}
+
+/// Number of tokens generated for all the candidates, if known.
+fn completion_tokens(candidates: &[Candidate], token_counter: &TokenCounter) -> Option<usize> {
+    candidates
+        .iter()
+        .map(|candidate| {
+            candidate
+                .usage_tracker
+                .completion_tokens(token_counter, &candidate.text)
+        })
+        .sum()
+}
+
+/// The prompts the model completes, rendered with the suffix by the fill-in-the-middle template
+/// of the model when a suffix is given.
+fn build_prompts(
+    request: &CompletionCreateParams,
+    model: &ModelRoute,
+    token_counter: &TokenCounter,
+) -> anyhow::Result<Vec<Prompt>> {
+    if request.prompt.is_empty() {
+        anyhow::bail!(InvalidRequest::param("prompt", "prompt must not be empty"));
+    }
+    let fim = match request.suffix.as_deref() {
+        None | Some("") => None,
+        Some(suffix) => match &model.fim_template {
+            Some(template) => Some((template, suffix)),
+            None => anyhow::bail!(InvalidRequest::param(
+                "suffix",
+                format!(
+                    "suffix is not supported by model '{}', it has no fill-in-the-middle template",
+                    request.model
+                )
+            )),
+        },
+    };
+    request
+        .prompt
+        .iter()
+        .map(|prompt| match (fim, prompt) {
+            (Some((template, suffix)), prompt) => {
+                let prompt = prompt_text(prompt, token_counter)?;
+                Ok(Prompt::Text(template.render(&prompt, suffix)?))
+            }
+            // Models that do not take token ids are given the decoded text instead.
+            (None, Prompt::Tokens(_)) if !model.input_ids => {
+                Ok(Prompt::Text(prompt_text(prompt, token_counter)?))
+            }
+            (None, prompt) => Ok(prompt.clone()),
+        })
+        .collect()
+}
+
+/// Build the requests of every candidate completion of every prompt, `best_of` of them per
+/// prompt, or `n` by default.
+fn build_triton_requests(
+    model: &ModelRoute,
+    request: &CompletionCreateParams,
+    prompts: &[Prompt],
+    vllm_additional_outputs: bool,
+    max_n: usize,
+) -> anyhow::Result<Vec<ModelInferRequest>> {
+    if request.n == 0 {
+        anyhow::bail!(InvalidRequest::param("n", "n must be at least 1"));
+    }
+    let best_of = request.best_of.unwrap_or(request.n);
+    if best_of < request.n {
+        anyhow::bail!(InvalidRequest::param(
+            "best_of",
+            format!("best_of must be greater than or equal to n={}", request.n)
+        ));
+    }
+    // Every candidate is generated, so `best_of` is bounded along with `n`.
+    if best_of > max_n {
+        let param = if request.best_of.is_some() {
+            "best_of"
+        } else {
+            "n"
+        };
+        anyhow::bail!(InvalidRequest::param(
+            param,
+            format!("{} must be less than or equal to {}", param, max_n)
+        ));
+    }
+    // The candidates of every prompt are separate inferences, which share the turn of the request.
+    let inferences = prompts.len().saturating_mul(best_of);
+    if inferences > max_n {
+        anyhow::bail!(InvalidRequest::param(
+            "prompt",
+            format!(
+                "the request generates {} completions for {} prompts, more than the maximum of {}",
+                inferences,
+                prompts.len(),
+                max_n
+            )
+        ));
+    }
+    let ranked = best_of > request.n;
+    if ranked && request.stream {
+        anyhow::bail!(InvalidRequest::param(
+            "best_of",
+            "best_of cannot be greater than n when streaming"
+        ));
+    }
+
+    match model.backend {
+        Backend::Vllm => {
+            let sampling_params = sampling_params(request, model)?;
+            prompts
+                .iter()
+                .flat_map(|prompt| (0..best_of).map(move |i| (prompt, i)))
+                .map(|(prompt, i)| {
+                    let mut sampling_params = sampling_params.clone();
+                    if let Some(seed) = request.seed {
+                        sampling_params.seed = Some(seed.wrapping_add(i) as u64);
+                    }
+                    build_vllm_request(
+                        model,
+                        request,
+                        prompt,
+                        &sampling_params,
+                        ranked,
+                        vllm_additional_outputs,
+                    )
+                })
+                .collect()
+        }
+        Backend::TensorrtLlm => {
+            if ranked {
+                anyhow::bail!(InvalidRequest::param(
+                    "best_of",
+                    "best_of is not supported by the tensorrt-llm backend"
+                ));
+            }
+            if request.logprobs.is_some() {
+                anyhow::bail!(InvalidRequest::param(
+                    "logprobs",
+                    "logprobs is not supported by the tensorrt-llm backend"
+                ));
+            }
+            // Like with vLLM, every choice is a separate request rather than a beam.
+            prompts
+                .iter()
+                .flat_map(|prompt| (0..best_of).map(move |i| (prompt, i)))
+                .map(|(prompt, i)| {
+                    let seed = request.seed.map(|seed| seed.wrapping_add(i) as u64);
+                    build_tensorrt_llm_request(model, request, prompt, seed)
+                })
+                .collect()
+        }
+    }
+}
+
+/// The token ids of a prompt, as the `input_ids` tensor of the models taking them.
+fn input_ids(tokens: &[u32]) -> anyhow::Result<Vec<i32>> {
+    tokens
+        .iter()
+        .map(|&token| match i32::try_from(token) {
+            Ok(token) => Ok(token),
+            Err(_) => anyhow::bail!(InvalidRequest::param(
+                "prompt",
+                format!("token id {} is out of range", token)
+            )),
+        })
+        .collect()
+}
+
+/// Request for the vLLM backend, which takes the sampling parameters as JSON.
+fn build_vllm_request(
+    model: &ModelRoute,
+    request: &CompletionCreateParams,
+    prompt: &Prompt,
+    sampling_params: &SamplingParams,
+    ranked: bool,
+    vllm_additional_outputs: bool,
+) -> anyhow::Result<ModelInferRequest> {
+    let sampling_parameters = serde_json::to_string(sampling_params)?;
+
+    let builder = Builder::new()
+        .model_name(model.triton_model.clone())
+        .model_version(model.triton_model_version.clone());
+    let builder = match prompt {
+        Prompt::Text(text) => builder.input(
+            "text_input",
+            [1],
+            InferTensorData::Bytes(vec![text.as_bytes().to_vec()]),
+        ),
+        Prompt::Tokens(tokens) => builder.input(
+            "input_ids",
+            [tokens.len() as i64],
+            InferTensorData::Int32(input_ids(tokens)?),
+        ),
+    };
+    let mut builder = builder
         .input(
+            "sampling_parameters",
+            [1],
+            InferTensorData::Bytes(vec![sampling_parameters.into_bytes()]),
+        )
+        .input("stream", [1], InferTensorData::Bool(vec![request.stream]))
+        .output("text_output");
+
+    if vllm_additional_outputs {
+        builder = additional_outputs(builder);
+    }
+    if ranked {
+        builder = builder
+            .input(
+                "return_cumulative_logprob",
+                [1],
+                InferTensorData::Bool(vec![true]),
+            )
+            .output(CUMULATIVE_LOGPROB);
+    }
+    if request.logprobs.is_some() {
+        builder = request_logprobs(builder);
+    }
+
+    builder.build().context("failed to build triton request")
+}
+
+/// The maximum number of tokens generated per choice, the request overriding the default sampling
+/// parameters of the model, which the tensorrt-llm backend ignores.
+fn max_tokens(request: &CompletionCreateParams, model: &ModelRoute) -> usize {
+    let model_default = match model.backend {
+        Backend::Vllm => model
+            .sampling_parameters
+            .get("max_tokens")
+            .and_then(serde_json::Value::as_u64)
+            .map(|max_tokens| max_tokens as usize),
+        Backend::TensorrtLlm => None,
+    };
+    request
+        .max_tokens
+        .or(model_default)
+        .unwrap_or_else(default_max_tokens)
+}
+
+/// Map the OpenAI request fields onto the vLLM sampling parameters, on top of the defaults of the
+/// model.
+fn sampling_params(
+    request: &CompletionCreateParams,
+    model: &ModelRoute,
+) -> anyhow::Result<SamplingParams> {
+    let mut params =
+        SamplingParams::from_value(serde_json::Value::Object(model.sampling_parameters.clone()))?;
+    params.max_tokens = Some(max_tokens(request, model));
+    params.temperature = request.temperature.or(params.temperature);
+    params.top_p = request.top_p.or(params.top_p);
+    params.frequency_penalty = request.frequency_penalty.or(params.frequency_penalty);
+    params.presence_penalty = request.presence_penalty.or(params.presence_penalty);
+    params.seed = request.seed.map(|seed| seed as u64).or(params.seed);
+    params.logprobs = request.logprobs.or(params.logprobs);
+    if let Some(stop) = &request.stop {
+        params.stop = Some(stop.clone());
+    }
+    if let Some(logit_bias) = &request.logit_bias {
+        params.set_logit_bias(logit_bias)?;
+    }
+
+    params.validate()?;
+    Ok(params)
+}
+
+/// Request for the TensorRT-LLM backend, which takes every sampling parameter as a tensor.
+fn build_tensorrt_llm_request(
+    model: &ModelRoute,
+    request: &CompletionCreateParams,
+    prompt: &Prompt,
+    seed: Option<u64>,
+) -> anyhow::Result<ModelInferRequest> {
+    let stop = request
+        .stop
+        .clone()
+        .unwrap_or_else(|| vec!["</s>".to_string()]);
+    let builder = Builder::new()
+        .model_name(model.triton_model.clone())
+        .model_version(model.triton_model_version.clone());
+    let builder = match prompt {
+        Prompt::Text(text) => builder.input(
             "text_input",
             [1, 1],
-            InferTensorData::Bytes(
-                request
-                    .prompt
-                    .into_iter()
-                    .map(|s| s.as_bytes().to_vec())
-                    .collect(),
+            InferTensorData::Bytes(vec![text.as_bytes().to_vec()]),
+        ),
+        Prompt::Tokens(tokens) => builder
+            .input(
+                "input_ids",
+                [1, tokens.len() as i64],
+                InferTensorData::Int32(input_ids(tokens)?),
+            )
+            .input(
+                "input_lengths",
+                [1, 1],
+                InferTensorData::Int32(vec![tokens.len() as i32]),
             ),
-        )
+    };
+    let mut builder = builder
         .input(
             "max_tokens",
             [1, 1],
-            InferTensorData::Int32(vec![request.max_tokens as i32]),
+            InferTensorData::Int32(vec![max_tokens(request, model) as i32]),
         )
         .input(
             "bad_words",
@@ -219,32 +850,25 @@ fn build_triton_request(request: CompletionCreateParams) -> anyhow::Result<Model
         )
         .input(
             "stop_words",
-            [1, request.stop.as_ref().map_or(1, |s| s.len() as i64)],
-            InferTensorData::Bytes(
-                request
-                    .stop
-                    .unwrap_or_else(|| vec!["</s>".to_string()])
-                    .into_iter()
-                    .map(|s| s.into_bytes())
-                    .collect(),
-            ),
+            [1, stop.len() as i64],
+            InferTensorData::Bytes(stop.into_iter().map(|s| s.into_bytes()).collect()),
         )
-        .input("top_p", [1, 1], InferTensorData::FP32(vec![request.top_p]))
         .input(
-            "temperature",
+            "top_p",
             [1, 1],
-            InferTensorData::FP32(vec![request.temperature]),
+            InferTensorData::FP32(vec![request.top_p.unwrap_or(1.0)]),
         )
         .input(
-            "presence_penalty",
+            "temperature",
             [1, 1],
-            InferTensorData::FP32(vec![request.presence_penalty]),
+            InferTensorData::FP32(vec![request.temperature.unwrap_or(1.0)]),
         )
         .input(
-            "beam_width",
+            "presence_penalty",
             [1, 1],
-            InferTensorData::Int32(vec![request.n as i32]),
+            InferTensorData::FP32(vec![request.presence_penalty.unwrap_or(0.0)]),
         )
+        .input("beam_width", [1, 1], InferTensorData::Int32(vec![1]))
         .input(
             "stream",
             [1, 1],
@@ -252,12 +876,8 @@ fn build_triton_request(request: CompletionCreateParams) -> anyhow::Result<Model
         )
         .output("text_output");
 
-    if request.seed.is_some() {
-        builder = builder.input(
-            "random_seed",
-            [1, 1],
-            InferTensorData::UInt64(vec![request.seed.unwrap() as u64]),
-        );
+    if let Some(seed) = seed {
+        builder = builder.input("random_seed", [1, 1], InferTensorData::UInt64(vec![seed]));
     }
 
     builder.build().context("failed to build triton request")
@@ -270,34 +890,30 @@ pub(crate) struct CompletionCreateParams {
     model: String,
     /// The prompt(s) to generate completions for, encoded as a string, array of strings, array of
     /// tokens, or array of token arrays.
-    #[serde(deserialize_with = "string_or_seq_string")]
-    prompt: Vec<String>,
+    #[serde(deserialize_with = "prompts")]
+    prompt: Vec<Prompt>,
     /// Generates best_of completions server-side and returns the "best" (the one with the highest
     /// log probability per token). Results cannot be streamed.
-    #[serde(default = "default_best_of")]
-    best_of: usize,
+    best_of: Option<usize>,
     /// Echo back the prompt in addition to the completion
     #[serde(default = "default_echo")]
     echo: bool,
     /// Number between -2.0 and 2.0. Positive values penalize new tokens based on their existing
     /// frequency in the text so far, decreasing the model's likelihood to repeat the same line
     /// verbatim.
-    #[serde(default = "default_frequency_penalty")]
-    frequency_penalty: f32,
+    frequency_penalty: Option<f32>,
     /// Modify the likelihood of specified tokens appearing in the completion.
     logit_bias: Option<HashMap<String, f32>>,
     /// Include the log probabilities on the logprobs most likely tokens, as well the chosen tokens.
     logprobs: Option<usize>,
     /// The maximum number of tokens to generate in the completion.
-    #[serde(default = "default_max_tokens")]
-    max_tokens: usize,
+    max_tokens: Option<usize>,
     /// How many completions to generate for each prompt.
     #[serde(default = "default_n")]
     n: usize,
     /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they
     /// appear in the text so far, increasing the model's likelihood to talk about new topics.
-    #[serde(default = "default_presence_penalty")]
-    presence_penalty: f32,
+    presence_penalty: Option<f32>,
     /// If specified, our system will make a best effort to sample deterministically, such that
     /// repeated requests with the same seed and parameters should return the same result.
     seed: Option<usize>,
@@ -307,17 +923,17 @@ pub(crate) struct CompletionCreateParams {
     /// Whether to stream back partial progress.
     #[serde(default = "default_stream")]
     stream: bool,
+    /// Options for streaming response. Only set this when you set `stream: true`.
+    stream_options: Option<StreamOptions>,
     /// The suffix that comes after a completion of inserted text.
     suffix: Option<String>,
     /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make the
     /// output more random, while lower values like 0.2 will make it more focused and deterministic.
-    #[serde(default = "default_temperature")]
-    temperature: f32,
+    temperature: Option<f32>,
     /// An alternative to sampling with temperature, called nucleus sampling, where the model
     /// considers the results of the tokens with top_p probability mass. So 0.1 means only the
     /// tokens comprising the top 10% probability mass are considered.
-    #[serde(default = "default_top_p")]
-    top_p: f32,
+    top_p: Option<f32>,
     /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect
     /// abuse.
     user: Option<String>,
@@ -335,18 +951,50 @@ struct Completion {
     model: String,
     /// The list of completion choices the model generated for the input prompt.
     choices: Vec<CompletionChoice>,
-    /// Usage statistics for the completion request.
-    usage: Option<Usage>,
+    /// Usage statistics for the completion request. Chunks only have it, null but in the last one,
+    /// when `stream_options.include_usage` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Option<Usage>>,
 }
 
 #[derive(Serialize, Debug)]
 struct CompletionChoice {
     text: String,
     index: usize,
-    logprobs: Option<()>,
+    logprobs: Option<CompletionLogprobs>,
     finish_reason: Option<FinishReason>,
 }
 
+#[derive(Serialize, Debug, Default)]
+struct CompletionLogprobs {
+    /// The generated tokens.
+    tokens: Vec<String>,
+    /// Log probability of every generated token.
+    token_logprobs: Vec<f32>,
+    /// The most likely tokens at the position of every generated token, with their log
+    /// probability.
+    top_logprobs: Vec<HashMap<String, f32>>,
+    /// Offset of every generated token in the text of the choice, in characters.
+    text_offset: Vec<usize>,
+}
+
+impl CompletionLogprobs {
+    /// The log probabilities of consecutive tokens, the first starting at `text_offset`.
+    fn new(logprobs: &[TokenLogprob], mut text_offset: usize) -> Self {
+        let mut completion_logprobs = Self::default();
+        for logprob in logprobs {
+            completion_logprobs.tokens.push(logprob.token.clone());
+            completion_logprobs.token_logprobs.push(logprob.logprob);
+            completion_logprobs
+                .top_logprobs
+                .push(logprob.top_logprobs.iter().cloned().collect());
+            completion_logprobs.text_offset.push(text_offset);
+            text_offset += logprob.token.chars().count();
+        }
+        completion_logprobs
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -369,18 +1017,10 @@ struct Usage {
     pub total_tokens: usize,
 }
 
-fn default_best_of() -> usize {
-    1
-}
-
 fn default_echo() -> bool {
     false
 }
 
-fn default_frequency_penalty() -> f32 {
-    0.0
-}
-
 fn default_max_tokens() -> usize {
     16
 }
@@ -389,18 +1029,327 @@ fn default_n() -> usize {
     1
 }
 
-fn default_presence_penalty() -> f32 {
-    0.0
-}
-
 fn default_stream() -> bool {
     false
 }
 
-fn default_temperature() -> f32 {
-    1.0
-}
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use clap::Parser;
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::history::HistoryBuilder;
+    use crate::registry::ModelRegistry;
+    use crate::triton::pool::EndpointPool;
 
-fn default_top_p() -> f32 {
-    1.0
+    fn route(args: &[&str]) -> ModelRoute {
+        let config = Config::parse_from(["openai_trtllm"].iter().chain(args));
+        let pool = EndpointPool::new(
+            &config.triton_endpoint,
+            config.load_balancing,
+            Duration::from_secs(config.ejection_secs),
+        )
+        .unwrap();
+        let history_builder = HistoryBuilder::new(&None, &None).unwrap();
+        ModelRegistry::new(&config, pool, history_builder)
+            .unwrap()
+            .resolve("model")
+    }
+
+    fn requests(model: &ModelRoute, request: serde_json::Value) -> Vec<ModelInferRequest> {
+        let request: CompletionCreateParams = serde_json::from_value(request).unwrap();
+        let prompts = build_prompts(&request, model, &TokenCounter::default()).unwrap();
+        build_triton_requests(model, &request, &prompts, false, 128).unwrap()
+    }
+
+    fn input_names(request: &ModelInferRequest) -> Vec<&str> {
+        request
+            .inputs
+            .iter()
+            .map(|input| input.name.as_str())
+            .collect()
+    }
+
+    fn bytes_input<'a>(request: &'a ModelInferRequest, name: &str) -> &'a [u8] {
+        let input = request.inputs.iter().find(|input| input.name == name);
+        &input.unwrap().contents.as_ref().unwrap().bytes_contents[0]
+    }
+
+    #[tokio::test]
+    pub async fn test_vllm_request() {
+        let requests = requests(
+            &route(&[]),
+            json!({
+                "model": "model",
+                "prompt": "Say this is a test",
+                "temperature": 0.5,
+                "stop": ["\n"],
+            }),
+        );
+
+        assert_eq!(1, requests.len());
+        assert_eq!(
+            vec!["text_input", "sampling_parameters", "stream"],
+            input_names(&requests[0])
+        );
+        assert_eq!(
+            json!({"temperature": 0.5, "stop": ["\n"], "max_tokens": 16}),
+            serde_json::from_slice::<serde_json::Value>(bytes_input(
+                &requests[0],
+                "sampling_parameters"
+            ))
+            .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_max_tokens() {
+        let mut model = route(&[]);
+        model
+            .sampling_parameters
+            .insert("max_tokens".to_string(), json!(256));
+        let max_tokens = |request: serde_json::Value| {
+            let requests = requests(&model, request);
+            let sampling_parameters: serde_json::Value =
+                serde_json::from_slice(bytes_input(&requests[0], "sampling_parameters")).unwrap();
+            sampling_parameters["max_tokens"].clone()
+        };
+
+        assert_eq!(256, max_tokens(json!({"model": "model", "prompt": "test"})));
+        assert_eq!(
+            32,
+            max_tokens(json!({"model": "model", "prompt": "test", "max_tokens": 32}))
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_tensorrt_llm_request() {
+        let model = route(&["--backend", "tensorrt-llm"]);
+        // Every choice of every prompt is a request, in the order of the choices.
+        let batched = requests(
+            &model,
+            json!({"model": "model", "prompt": ["first", "second"], "n": 2}),
+        );
+        let prompts: Vec<_> = batched
+            .iter()
+            .map(|request| bytes_input(request, "text_input"))
+            .collect();
+        assert_eq!(vec![&b"first"[..], b"first", b"second", b"second"], prompts);
+
+        let requests = requests(
+            &model,
+            json!({
+                "model": "model",
+                "prompt": "Say this is a test",
+            }),
+        );
+
+        assert_eq!(1, requests.len());
+        assert_eq!(
+            vec![
+                "text_input",
+                "max_tokens",
+                "bad_words",
+                "stop_words",
+                "top_p",
+                "temperature",
+                "presence_penalty",
+                "beam_width",
+                "stream"
+            ],
+            input_names(&requests[0])
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_best_of() {
+        let model = route(&[]);
+        let requests = requests(
+            &model,
+            json!({"model": "model", "prompt": "test", "best_of": 3, "seed": 7}),
+        );
+        assert_eq!(3, requests.len());
+        for (i, request) in requests.iter().enumerate() {
+            assert!(input_names(request).contains(&"return_cumulative_logprob"));
+            let sampling_parameters: serde_json::Value =
+                serde_json::from_slice(bytes_input(request, "sampling_parameters")).unwrap();
+            assert_eq!(7 + i, sampling_parameters["seed"]);
+        }
+
+        for invalid in [
+            json!({"model": "model", "prompt": "test", "best_of": 1, "n": 2}),
+            json!({"model": "model", "prompt": "test", "best_of": 2, "stream": true}),
+            json!({"model": "model", "prompt": "test", "best_of": 129}),
+            json!({"model": "model", "prompt": "test", "n": 129}),
+            json!({"model": "model", "prompt": ["first", "second"], "n": 65}),
+        ] {
+            let request: CompletionCreateParams = serde_json::from_value(invalid).unwrap();
+            let prompts = build_prompts(&request, &model, &TokenCounter::default()).unwrap();
+            assert!(build_triton_requests(&model, &request, &prompts, false, 128).is_err());
+        }
+
+        let candidates = [Some(-3.0), None, Some(-1.0)]
+            .into_iter()
+            .map(|cumulative_logprob| Candidate {
+                cumulative_logprob,
+                ..Default::default()
+            })
+            .collect();
+        let best: Vec<_> = best_candidates(candidates, 2)
+            .iter()
+            .map(|candidate| candidate.cumulative_logprob)
+            .collect();
+        assert_eq!(vec![Some(-1.0), Some(-3.0)], best);
+    }
+
+    #[tokio::test]
+    pub async fn test_batched_prompts() {
+        let requests = requests(
+            &route(&[]),
+            json!({"model": "model", "prompt": ["first", "second"], "n": 2}),
+        );
+        let prompts: Vec<_> = requests
+            .iter()
+            .map(|request| bytes_input(request, "text_input"))
+            .collect();
+        assert_eq!(vec![&b"first"[..], b"first", b"second", b"second"], prompts);
+    }
+
+    #[tokio::test]
+    pub async fn test_suffix() {
+        let request: CompletionCreateParams = serde_json::from_value(json!({
+            "model": "model",
+            "prompt": "def add(a, b):",
+            "suffix": "    return c",
+        }))
+        .unwrap();
+
+        let model = route(&[
+            "--fim-template",
+            "<PRE> {{ prompt }} <SUF>{{ suffix }} <MID>",
+        ]);
+        assert_eq!(
+            vec![Prompt::Text(
+                "<PRE> def add(a, b): <SUF>    return c <MID>".to_string()
+            )],
+            build_prompts(&request, &model, &TokenCounter::default()).unwrap()
+        );
+        assert!(build_prompts(&request, &route(&[]), &TokenCounter::default()).is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_token_prompts() {
+        let request: CompletionCreateParams = serde_json::from_value(json!({
+            "model": "model",
+            "prompt": [[1, 2], [3]],
+        }))
+        .unwrap();
+        assert_eq!(
+            vec![Prompt::Tokens(vec![1, 2]), Prompt::Tokens(vec![3])],
+            request.prompt
+        );
+
+        let mut model = route(&[]);
+        // Without a tokenizer to decode them, token ids need a model taking input_ids.
+        assert!(build_prompts(&request, &model, &TokenCounter::default()).is_err());
+        model.input_ids = true;
+        let requests = requests(&model, json!({"model": "model", "prompt": [1, 2]}));
+        assert_eq!(
+            vec!["input_ids", "sampling_parameters", "stream"],
+            input_names(&requests[0])
+        );
+        assert_eq!(
+            vec![1, 2],
+            requests[0].inputs[0]
+                .contents
+                .as_ref()
+                .unwrap()
+                .int_contents
+        );
+
+        // Echoing the prompt needs its text all the same, before any request is sent.
+        let request: CompletionCreateParams = serde_json::from_value(json!({
+            "model": "model",
+            "prompt": [1, 2],
+            "echo": true,
+        }))
+        .unwrap();
+        let error = AppError::from(echo_texts(&request, &TokenCounter::default()).unwrap_err());
+        assert_eq!("echo", error.body()["error"]["param"]);
+
+        // Token ids that don't fit the input_ids tensor are rejected rather than wrapped.
+        let request: CompletionCreateParams = serde_json::from_value(json!({
+            "model": "model",
+            "prompt": [1, 2147483648u32],
+        }))
+        .unwrap();
+        let prompts = build_prompts(&request, &model, &TokenCounter::default()).unwrap();
+        let error = build_triton_requests(&model, &request, &prompts, false, 128).unwrap_err();
+        assert_eq!("prompt", AppError::from(error).body()["error"]["param"]);
+
+        let candidates = new_candidates(&[Prompt::Tokens(vec![1, 2])], 2, None);
+        let usage = total_usage(&candidates, &TokenCounter::default(), &request.prompt[..1]);
+        assert_eq!(2, usage.prompt_tokens);
+    }
+
+    #[tokio::test]
+    pub async fn test_logprobs() {
+        let requests = requests(
+            &route(&[]),
+            json!({"model": "model", "prompt": "test", "logprobs": 2}),
+        );
+        assert!(input_names(&requests[0]).contains(&"return_logprobs"));
+        let sampling_parameters: serde_json::Value =
+            serde_json::from_slice(bytes_input(&requests[0], "sampling_parameters")).unwrap();
+        assert_eq!(2, sampling_parameters["logprobs"]);
+
+        let logprobs = CompletionLogprobs::new(
+            &[
+                TokenLogprob {
+                    token: "Hé".to_string(),
+                    logprob: -0.5,
+                    top_logprobs: vec![("Hé".to_string(), -0.5)],
+                },
+                TokenLogprob {
+                    token: "llo".to_string(),
+                    logprob: -0.25,
+                    top_logprobs: vec![("llo".to_string(), -0.25)],
+                },
+            ],
+            4,
+        );
+        assert_eq!(
+            json!({
+                "tokens": ["Hé", "llo"],
+                "token_logprobs": [-0.5, -0.25],
+                "top_logprobs": [{"Hé": -0.5}, {"llo": -0.25}],
+                "text_offset": [4, 6],
+            }),
+            serde_json::to_value(logprobs).unwrap()
+        );
+    }
+
+    #[test]
+    pub fn test_usage_chunk() {
+        let chunk = |usage| Completion {
+            id: "cmpl".to_string(),
+            object: "text_completion".to_string(),
+            created: 0,
+            model: "model".to_string(),
+            choices: vec![],
+            usage,
+        };
+        assert!(serde_json::to_value(chunk(None))
+            .unwrap()
+            .get("usage")
+            .is_none());
+        assert_eq!(
+            serde_json::Value::Null,
+            serde_json::to_value(chunk(Some(None))).unwrap()["usage"]
+        );
+    }
 }
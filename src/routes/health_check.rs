@@ -1 +1,125 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::registry::ModelRoute;
+use crate::state::AppState;
+use crate::triton::ModelReadyRequest;
+
+/// Maximum time to wait for the answer of Triton to a readiness probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the readiness of the dependencies is reused, to keep frequent probes cheap.
+const CACHE_TTL: Duration = Duration::from_secs(2);
+
 pub async fn health_check() {}
+
+/// The process is up and serving HTTP.
+pub(crate) async fn liveness() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "live" }))
+}
+
+/// Ready once every Triton deployment has a ready endpoint and every configured model is ready.
+pub(crate) async fn readiness(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let readiness = state.readiness_cache.get_or_probe(&state).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+#[derive(Clone, Default)]
+pub struct ReadinessCache(Arc<Mutex<Option<(Instant, Readiness)>>>);
+
+impl ReadinessCache {
+    async fn get_or_probe(&self, state: &AppState) -> Readiness {
+        // Concurrent probes wait for the one in progress instead of hitting Triton again.
+        let mut cached = self.0.lock().await;
+        if let Some((probed_at, readiness)) = cached.as_ref() {
+            if probed_at.elapsed() < CACHE_TTL {
+                return readiness.clone();
+            }
+        }
+        let readiness = probe(state).await;
+        *cached = Some((Instant::now(), readiness.clone()));
+        readiness
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct Readiness {
+    ready: bool,
+    triton: Vec<Dependency>,
+    models: Vec<Dependency>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct Dependency {
+    name: String,
+    ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Dependency {
+    fn new(name: String, ready: anyhow::Result<bool>) -> Self {
+        match ready {
+            Ok(ready) => Self {
+                name,
+                ready,
+                error: None,
+            },
+            Err(e) => Self {
+                name,
+                ready: false,
+                error: Some(match e.downcast_ref::<tonic::Status>() {
+                    Some(status) => status.message().to_string(),
+                    None => e.to_string(),
+                }),
+            },
+        }
+    }
+}
+
+async fn probe(state: &AppState) -> Readiness {
+    let mut ready = true;
+    let mut triton = Vec::new();
+    for pool in state.models.pools() {
+        let endpoints = pool.probe(PROBE_TIMEOUT).await;
+        ready &= endpoints.iter().any(|(_, ready)| matches!(ready, Ok(true)));
+        triton.extend(
+            endpoints
+                .into_iter()
+                .map(|(url, ready)| Dependency::new(url, ready)),
+        );
+    }
+
+    let mut models = Vec::new();
+    for (name, route) in state.models.configured() {
+        let model = Dependency::new(name.clone(), model_ready(route).await);
+        ready &= model.ready;
+        models.push(model);
+    }
+
+    Readiness {
+        ready,
+        triton,
+        models,
+    }
+}
+
+async fn model_ready(route: &ModelRoute) -> anyhow::Result<bool> {
+    let request = ModelReadyRequest {
+        name: route.triton_model.clone(),
+        version: route.triton_model_version.clone(),
+    };
+    let mut client = route.pool.client();
+    let response = tokio::time::timeout(PROBE_TIMEOUT, client.model_ready(request)).await??;
+    Ok(response.into_inner().ready)
+}
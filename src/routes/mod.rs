@@ -1,7 +1,10 @@
 pub(crate) use chat::compat_chat_completions;
 pub(crate) use completions::compat_completions;
-pub(crate) use health_check::health_check;
+pub use health_check::ReadinessCache;
+pub(crate) use health_check::{health_check, liveness, readiness};
+pub(crate) use models::{list_models, retrieve_model};
 
 pub(crate) mod chat;
 mod completions;
 mod health_check;
+mod models;
@@ -0,0 +1,169 @@
+//! Implements the OpenAI models API on top of the model repository of Triton.
+//!
+//! https://platform.openai.com/docs/api-reference/models
+use anyhow::Context;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::{Extension, Json};
+use serde::Serialize;
+use tracing::instrument;
+
+use crate::auth::{authorize_model, Identity};
+use crate::error::AppError;
+use crate::registry::ModelRoute;
+use crate::state::AppState;
+use crate::triton::pool::EndpointPool;
+use crate::triton::telemetry::propagate_context;
+use crate::triton::{ModelMetadataRequest, ModelReadyRequest, RepositoryIndexRequest};
+
+#[instrument(name = "list models", skip(state, identity))]
+pub(crate) async fn list_models(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    identity: Option<Extension<Identity>>,
+) -> Result<Json<ModelList>, AppError> {
+    let mut data = Vec::new();
+    for name in ready_models(&headers, state.models.pool()).await? {
+        if authorize_model(&identity, &name).is_err() {
+            continue;
+        }
+        let route = state.models.resolve(&name);
+        data.extend(listed_model(&headers, &name, &route).await);
+    }
+    // Configured models are only listed when the model they point to can serve requests.
+    for (id, route) in state.models.configured() {
+        if authorize_model(&identity, id).is_err() {
+            continue;
+        }
+        if data.iter().all(|model| &model.id != id) && is_ready(&headers, route).await {
+            data.extend(listed_model(&headers, id, route).await);
+        }
+    }
+
+    Ok(Json(ModelList {
+        object: "list".to_string(),
+        data,
+    }))
+}
+
+#[instrument(name = "retrieve model", skip(state, identity))]
+pub(crate) async fn retrieve_model(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    identity: Option<Extension<Identity>>,
+    Path(id): Path<String>,
+) -> Result<Json<Model>, AppError> {
+    authorize_model(&identity, &id)?;
+    let route = state.models.resolve(&id);
+    if !is_ready(&headers, &route).await {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            format!("The model '{}' does not exist", id),
+        )
+        .with_code("model_not_found"));
+    }
+
+    Ok(Json(model(&headers, &id, &route).await?))
+}
+
+/// Names of the models of the repository that are ready to serve requests.
+async fn ready_models(headers: &HeaderMap, pool: &EndpointPool) -> anyhow::Result<Vec<String>> {
+    let mut request = tonic::Request::new(RepositoryIndexRequest {
+        repository_name: String::new(),
+        ready: true,
+    });
+    propagate_context(&mut request, headers);
+
+    let response = pool
+        .client()
+        .repository_index(request)
+        .await
+        .context("failed to call triton grpc method repository_index")?
+        .into_inner();
+
+    let mut names: Vec<_> = response
+        .models
+        .into_iter()
+        .filter(|model| model.state == "READY")
+        .map(|model| model.name)
+        .collect();
+    // Every ready version of a model is listed separately.
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Whether the Triton model of `route` can serve requests.
+async fn is_ready(headers: &HeaderMap, route: &ModelRoute) -> bool {
+    let mut request = tonic::Request::new(ModelReadyRequest {
+        name: route.triton_model.clone(),
+        version: route.triton_model_version.clone(),
+    });
+    propagate_context(&mut request, headers);
+
+    match route.pool.client().model_ready(request).await {
+        Ok(response) => response.into_inner().ready,
+        Err(status) => {
+            tracing::debug!("model {} is not ready: {}", route.triton_model, status);
+            false
+        }
+    }
+}
+
+/// Describe a model of the list, which is left out as not ready when Triton can't describe it.
+async fn listed_model(headers: &HeaderMap, id: &str, route: &ModelRoute) -> Option<Model> {
+    match model(headers, id, route).await {
+        Ok(model) => Some(model),
+        Err(e) => {
+            tracing::warn!("model {} is not listed: {:#}", id, e);
+            None
+        }
+    }
+}
+
+/// Describe the Triton model of `route` under the public `id`.
+async fn model(headers: &HeaderMap, id: &str, route: &ModelRoute) -> anyhow::Result<Model> {
+    let mut request = tonic::Request::new(ModelMetadataRequest {
+        name: route.triton_model.clone(),
+        version: route.triton_model_version.clone(),
+    });
+    propagate_context(&mut request, headers);
+
+    let metadata = route
+        .pool
+        .client()
+        .model_metadata(request)
+        .await
+        .context("failed to call triton grpc method model_metadata")?
+        .into_inner();
+
+    Ok(Model {
+        id: id.to_string(),
+        object: "model".to_string(),
+        // Triton does not know when a model was created.
+        created: 0,
+        owned_by: if metadata.platform.is_empty() {
+            "triton".to_string()
+        } else {
+            metadata.platform
+        },
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ModelList {
+    object: String,
+    data: Vec<Model>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Model {
+    /// The model identifier, which can be referenced in the API endpoints.
+    id: String,
+    /// The object type, which is always "model".
+    object: String,
+    /// The Unix timestamp (in seconds) when the model was created.
+    created: u64,
+    /// The organization that owns the model, the platform of the Triton model here.
+    owned_by: String,
+}
@@ -0,0 +1,253 @@
+//! Typed sampling parameters of the vLLM backend.
+//!
+//! The vLLM backend of Triton takes its sampling parameters as a JSON encoded `sampling_parameters`
+//! input, which is passed as keyword arguments to vLLM's `SamplingParams`. See
+//! https://docs.vllm.ai/en/latest/api/inference_params.html for the meaning of every field.
+use std::collections::HashMap;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::error::InvalidRequest;
+
+/// Most likely tokens vLLM returns the log probabilities of, by default.
+const MAX_LOGPROBS: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct SamplingParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_token_ids: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore_eos: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_special_tokens: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guided_decoding: Option<serde_json::Value>,
+    /// Any other parameter given by the client, forwarded untouched.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl SamplingParams {
+    /// Take the parameters given through the non-standard `sampling_parameters` request field as
+    /// the base that the OpenAI request fields are applied on.
+    pub fn from_value(value: serde_json::Value) -> anyhow::Result<Self> {
+        serde_json::from_value(value).map_err(|e| {
+            InvalidRequest::param(
+                "sampling_parameters",
+                format!("invalid sampling_parameters: {}", e),
+            )
+            .into()
+        })
+    }
+
+    /// Convert OpenAI's `logit_bias`, which maps token ids given as strings to a bias.
+    pub fn set_logit_bias(&mut self, logit_bias: &HashMap<String, f32>) -> anyhow::Result<()> {
+        let logit_bias = logit_bias
+            .iter()
+            .map(|(token, bias)| match token.parse::<u32>() {
+                Ok(token) => Ok((token, *bias)),
+                Err(_) => bail!(InvalidRequest::param(
+                    "logit_bias",
+                    format!("logit_bias keys must be token ids, got {:?}", token)
+                )),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        self.logit_bias = Some(logit_bias);
+        Ok(())
+    }
+
+    /// Check that every parameter is within the range accepted by the OpenAI API and vLLM.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        check_range("temperature", self.temperature, 0.0, 2.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+        check_range("min_p", self.min_p, 0.0, 1.0)?;
+        if let Some(top_p) = self.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                bail!(InvalidRequest::param(
+                    "top_p",
+                    format!("top_p must be in (0, 1], got {}", top_p)
+                ));
+            }
+        }
+        if let Some(repetition_penalty) = self.repetition_penalty {
+            if repetition_penalty <= 0.0 {
+                bail!(InvalidRequest::param(
+                    "repetition_penalty",
+                    format!(
+                        "repetition_penalty must be greater than 0, got {}",
+                        repetition_penalty
+                    )
+                ));
+            }
+        }
+        if let Some(top_k) = self.top_k {
+            if top_k == 0 || top_k < -1 {
+                bail!(InvalidRequest::param(
+                    "top_k",
+                    format!("top_k must be -1 (disable) or at least 1, got {}", top_k)
+                ));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            bail!(InvalidRequest::param(
+                "max_tokens",
+                "max_tokens must be at least 1"
+            ));
+        }
+        if let (Some(min_tokens), Some(max_tokens)) = (self.min_tokens, self.max_tokens) {
+            if min_tokens > max_tokens {
+                bail!(InvalidRequest::param(
+                    "min_tokens",
+                    format!(
+                        "min_tokens must be less than or equal to max_tokens={}, got {}",
+                        max_tokens, min_tokens
+                    )
+                ));
+            }
+        }
+        if let Some(logprobs) = self.logprobs {
+            if logprobs > MAX_LOGPROBS {
+                bail!(InvalidRequest::param(
+                    "logprobs",
+                    format!(
+                        "logprobs must be at most {}, got {}",
+                        MAX_LOGPROBS, logprobs
+                    )
+                ));
+            }
+        }
+        for bias in self.logit_bias.iter().flat_map(|bias| bias.values()) {
+            if !(-100.0..=100.0).contains(bias) {
+                bail!(InvalidRequest::param(
+                    "logit_bias",
+                    format!("logit_bias values must be in [-100, 100], got {}", bias)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_range(name: &str, value: Option<f32>, min: f32, max: f32) -> anyhow::Result<()> {
+    match value {
+        Some(value) if !(min..=max).contains(&value) => {
+            bail!(InvalidRequest::param(
+                name,
+                format!("{} must be in [{}, {}], got {}", name, min, max, value)
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_extra_parameters_are_kept() {
+        let mut params =
+            SamplingParams::from_value(json!({"top_k": 5, "use_beam_search": false})).unwrap();
+        params.temperature = Some(0.5);
+
+        assert_eq!(
+            json!({"top_k": 5, "temperature": 0.5, "use_beam_search": false}),
+            serde_json::to_value(&params).unwrap()
+        );
+    }
+
+    #[test]
+    pub fn test_logit_bias() {
+        let mut params = SamplingParams::default();
+        params
+            .set_logit_bias(&HashMap::from([("50256".to_string(), -100.0)]))
+            .unwrap();
+        assert_eq!(
+            json!({"logit_bias": {"50256": -100.0}}),
+            serde_json::to_value(&params).unwrap()
+        );
+        assert!(params
+            .set_logit_bias(&HashMap::from([("hello".to_string(), 1.0)]))
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_validate() {
+        let valid = SamplingParams {
+            temperature: Some(0.0),
+            top_p: Some(1.0),
+            top_k: Some(-1),
+            min_tokens: Some(16),
+            max_tokens: Some(16),
+            ..Default::default()
+        };
+        assert!(valid.validate().is_ok());
+
+        for invalid in [
+            SamplingParams {
+                temperature: Some(2.5),
+                ..Default::default()
+            },
+            SamplingParams {
+                top_p: Some(0.0),
+                ..Default::default()
+            },
+            SamplingParams {
+                top_k: Some(0),
+                ..Default::default()
+            },
+            SamplingParams {
+                frequency_penalty: Some(-3.0),
+                ..Default::default()
+            },
+            SamplingParams {
+                min_tokens: Some(17),
+                max_tokens: Some(16),
+                ..Default::default()
+            },
+            SamplingParams {
+                logit_bias: Some(HashMap::from([(1, 101.0)])),
+                ..Default::default()
+            },
+            SamplingParams {
+                logprobs: Some(21),
+                ..Default::default()
+            },
+        ] {
+            assert!(
+                invalid.validate().is_err(),
+                "{:?} should be invalid",
+                invalid
+            );
+        }
+    }
+}
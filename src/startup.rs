@@ -1,72 +1,121 @@
-use anyhow::Context;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::middleware;
 use axum::routing::{get, post};
 use axum::Router;
-use axum::middleware::{self, Next};
-use axum::http::{Request, StatusCode};
-use axum::response::Response;
-use axum::body::Body;
 use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
 
-use crate::config::Config;
+use crate::admission::Admission;
+use crate::auth::{auth_middleware, KeyStore};
+use crate::config::{Config, RouteGroup};
 use crate::history::HistoryBuilder;
-use crate::routes;
+use crate::metrics;
+use crate::rate_limit::{rate_limit_middleware, Limits, RateLimiter};
+use crate::registry::ModelRegistry;
+use crate::routes::{self, ReadinessCache};
 use crate::state::AppState;
-use crate::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
-
-async fn auth_middleware(
-    req: Request<Body>,
-    next: Next,
-    api_key: Option<String>,
-) -> Result<Response, StatusCode> {
-    if let Some(ref key) = api_key {
-        if let Some(auth_header) = req.headers().get("Authorization") {
-            if let Ok(auth_str) = auth_header.to_str() {
-                if auth_str == format!("Bearer {}", key) {
-                    return Ok(next.run(req).await);
-                }
-            }
-        }
-        Err(StatusCode::UNAUTHORIZED)
-    } else {
-        Ok(next.run(req).await)
-    }
-}
+use crate::tool_calls::ToolCallFormat;
+use crate::triton::pool::EndpointPool;
+use crate::triton::streams::Timeouts;
+use crate::usage::TokenCounter;
 
 pub async fn run_server(config: Config) -> anyhow::Result<()> {
-    tracing::info!("Connecting to triton endpoint: {}", config.triton_endpoint);
-    let grpc_client = GrpcInferenceServiceClient::connect(config.triton_endpoint)
-        .await
-        .context("failed to connect triton endpoint")?;
+    let pool = EndpointPool::new(
+        &config.triton_endpoint,
+        config.load_balancing,
+        Duration::from_secs(config.ejection_secs),
+    )?;
 
     let history_builder =
         HistoryBuilder::new(&config.history_template, &config.history_template_file)?;
+    let tool_call_format = ToolCallFormat::new(&config.tool_call_start, &config.tool_call_end)?;
+    let token_counter = TokenCounter::new(&config.tokenizer_file)?;
+    let models = ModelRegistry::new(&config, pool, history_builder)?;
+    // Triton may still be starting, requests are served as soon as it is ready.
+    models.watch();
     let state = AppState {
-        grpc_client,
-        history_builder,
+        models,
+        tool_call_format,
+        token_counter,
+        vllm_additional_outputs: config.vllm_additional_outputs,
+        readiness_cache: ReadinessCache::default(),
+        timeouts: Timeouts {
+            total: config.request_timeout_secs.map(Duration::from_secs),
+            first_token: config.first_token_timeout_secs.map(Duration::from_secs),
+        },
+        admission: Admission::new(&config),
+        max_n: config.max_n,
     };
 
-    let api_key = config.api_key.clone();
+    let keys = KeyStore::new(&config.api_key, &config.api_keys_file)?;
+    keys.watch();
+    let limits = Limits {
+        requests_per_minute: config.requests_per_minute,
+        tokens_per_minute: config.tokens_per_minute,
+        concurrent_requests: config.concurrent_requests,
+    };
+    limits.validate()?;
+    let limiter = RateLimiter::new(limits);
 
-    let app = Router::new()
+    let rate_limit = middleware::from_fn_with_state(limiter, rate_limit_middleware);
+    let completions = Router::new()
         .route("/v1/completions", post(routes::compat_completions))
         .route(
             "/v1/chat/completions",
             post(routes::compat_chat_completions),
         )
-        .route("/health_check", get(routes::health_check))
+        .route_layer(rate_limit.clone());
+    let groups = [
+        (
+            RouteGroup::Health,
+            Router::new()
+                .route("/health_check", get(routes::health_check))
+                .route("/health/live", get(routes::liveness))
+                .route("/health/ready", get(routes::readiness)),
+        ),
+        (
+            RouteGroup::Metrics,
+            Router::new().route("/metrics", get(metrics::prometheus_metrics)),
+        ),
+        (
+            RouteGroup::Models,
+            Router::new()
+                .route("/v1/models", get(routes::list_models))
+                .route("/v1/models/*model", get(routes::retrieve_model))
+                .route_layer(rate_limit),
+        ),
+    ];
+
+    // Completions always need a key, when keys are configured.
+    let mut public = Router::new();
+    let mut protected = completions;
+    for (group, routes) in groups {
+        if config.public_routes.contains(&group) {
+            public = public.merge(routes);
+        } else {
+            protected = protected.merge(routes);
+        }
+    }
+    let protected = protected.route_layer(middleware::from_fn_with_state(keys, auth_middleware));
+
+    let app = public
+        .merge(protected)
+        .route_layer(middleware::from_fn(metrics::track_requests))
         .with_state(state)
-        .layer(OtelAxumLayer::default())
-        .layer(middleware::from_fn(move |req, next| {
-            auth_middleware(req, next, api_key.clone())
-        }));
+        .layer(OtelAxumLayer::default());
 
     let address = format!("{}:{}", config.host, config.port);
     tracing::info!("Starting server at {}", address);
 
     let listener = tokio::net::TcpListener::bind(address).await.unwrap();
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    // The address of the client identifies it for rate limiting when authentication is disabled.
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
 
     Ok(())
 }
@@ -77,4 +126,5 @@ async fn shutdown_signal() {
         .expect("failed to install CTRL+C signal handler");
 
     opentelemetry::global::shutdown_tracer_provider();
+    crate::telemetry::shutdown_meter_provider();
 }
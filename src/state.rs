@@ -1,9 +1,19 @@
-use crate::history::HistoryBuilder;
-use crate::triton::grpc_inference_service_client::GrpcInferenceServiceClient;
-use tonic::transport::Channel;
+use crate::admission::Admission;
+use crate::registry::ModelRegistry;
+use crate::routes::ReadinessCache;
+use crate::tool_calls::ToolCallFormat;
+use crate::triton::streams::Timeouts;
+use crate::usage::TokenCounter;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub grpc_client: GrpcInferenceServiceClient<Channel>,
-    pub history_builder: HistoryBuilder,
+    pub models: ModelRegistry,
+    pub tool_call_format: ToolCallFormat,
+    pub token_counter: TokenCounter,
+    pub vllm_additional_outputs: bool,
+    pub readiness_cache: ReadinessCache,
+    pub timeouts: Timeouts,
+    pub admission: Admission,
+    /// Choices a completion request may ask for, across its prompts.
+    pub max_n: usize,
 }
@@ -0,0 +1,100 @@
+//! Gateway-side enforcement of the `stop` sequences of a request.
+//!
+//! The stop sequences are forwarded to the backend as well, but not every backend honors them and
+//! the tokenizer may produce a stop sequence spread over several streamed chunks. The matcher
+//! holds back any text that may turn into a stop sequence, and trims the output at the first
+//! complete match.
+use crate::utils::partial_suffix_len;
+
+pub(crate) struct StopMatcher {
+    stop: Vec<String>,
+    buffer: String,
+    stopped: bool,
+}
+
+impl StopMatcher {
+    pub fn new(stop: &[String]) -> Self {
+        Self {
+            stop: stop.iter().filter(|s| !s.is_empty()).cloned().collect(),
+            buffer: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// Whether a stop sequence has been generated. Any text pushed afterwards is discarded.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Feed the next piece of generated text and return the part that is safe to send.
+    pub fn push(&mut self, text: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        if self.stop.is_empty() {
+            return text.to_string();
+        }
+        self.buffer.push_str(text);
+
+        let first_match = self
+            .stop
+            .iter()
+            .filter_map(|stop| self.buffer.find(stop.as_str()))
+            .min();
+        if let Some(position) = first_match {
+            self.stopped = true;
+            self.buffer.truncate(position);
+            return std::mem::take(&mut self.buffer);
+        }
+
+        let keep = self
+            .stop
+            .iter()
+            .map(|stop| partial_suffix_len(&self.buffer, stop))
+            .max()
+            .unwrap_or(0);
+        self.buffer.drain(..self.buffer.len() - keep).collect()
+    }
+
+    /// Release the held back text once generation is over.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_stop_straddling_chunks() {
+        let mut matcher = StopMatcher::new(&["###".into(), "\nUser:".into()]);
+
+        assert_eq!("Hello", matcher.push("Hello"));
+        assert_eq!(" world", matcher.push(" world\nUs"));
+        assert!(!matcher.is_stopped());
+        assert_eq!("", matcher.push("er: hi"));
+        assert!(matcher.is_stopped());
+        assert_eq!("", matcher.push("more"));
+        assert_eq!("", matcher.finish());
+    }
+
+    #[test]
+    pub fn test_partial_match_is_released() {
+        let mut matcher = StopMatcher::new(&["###".into()]);
+
+        assert_eq!("a", matcher.push("a#"));
+        assert_eq!("#b", matcher.push("b"));
+        assert_eq!("c", matcher.push("c##"));
+        assert_eq!("##", matcher.finish());
+        assert!(!matcher.is_stopped());
+    }
+
+    #[test]
+    pub fn test_earliest_stop_wins() {
+        let mut matcher = StopMatcher::new(&["world".into(), "lo".into()]);
+
+        assert_eq!("Hel", matcher.push("Hello world"));
+        assert!(matcher.is_stopped());
+    }
+}
@@ -0,0 +1,46 @@
+//! Options of the streamed responses, shared by the chat and the completion routes.
+use serde::Deserialize;
+
+use crate::error::InvalidRequest;
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct StreamOptions {
+    /// If set, an additional chunk will be streamed before the data: [DONE] message, carrying the
+    /// token usage statistics for the entire request.
+    #[serde(default)]
+    include_usage: bool,
+}
+
+/// Whether the usage of the whole request is streamed in a last chunk, `stream_options` being only
+/// allowed when streaming.
+pub(crate) fn include_usage(
+    stream: bool,
+    stream_options: &Option<StreamOptions>,
+) -> anyhow::Result<bool> {
+    match stream_options {
+        Some(_) if !stream => anyhow::bail!(InvalidRequest::param(
+            "stream_options",
+            "stream_options is only allowed when stream is true"
+        )),
+        Some(options) => Ok(options.include_usage),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    pub fn test_include_usage() {
+        let options = |options: serde_json::Value| -> Option<StreamOptions> {
+            serde_json::from_value(options).unwrap()
+        };
+        assert!(include_usage(true, &options(json!({"include_usage": true}))).unwrap());
+        assert!(!include_usage(true, &options(json!({}))).unwrap());
+        assert!(!include_usage(false, &None).unwrap());
+        assert!(include_usage(false, &options(json!({"include_usage": true}))).is_err());
+    }
+}
@@ -1,6 +1,10 @@
+use std::sync::OnceLock;
+
+use opentelemetry::metrics::MetricsError;
 use opentelemetry::trace::TraceError;
 use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::metrics::MeterProvider;
 use opentelemetry_sdk::propagation::TraceContextPropagator;
 use opentelemetry_sdk::trace as sdktrace;
 use opentelemetry_sdk::{runtime, Resource};
@@ -26,6 +30,34 @@ fn init_tracer(name: &str, otlp_endpoint: &str) -> Result<sdktrace::Tracer, Trac
         .install_batch(runtime::Tokio)
 }
 
+/// Kept to flush the last metrics on shutdown.
+static METER_PROVIDER: OnceLock<MeterProvider> = OnceLock::new();
+
+/// Export metrics periodically to the collector, through the global meter provider.
+fn init_meter_provider(name: &str, otlp_endpoint: &str) -> Result<MeterProvider, MetricsError> {
+    opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(otlp_endpoint),
+        )
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            name.to_owned(),
+        )]))
+        .build()
+}
+
+/// Export the metrics that were not exported yet, if metrics are exported at all.
+pub fn shutdown_meter_provider() {
+    if let Some(provider) = METER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            tracing::warn!("failed to shut down the meter provider: {}", e);
+        }
+    }
+}
+
 /// Compose multiple layers into a `tracing`'s subscriber.
 ///
 /// # Implementation Notes
@@ -36,9 +68,15 @@ pub fn init_subscriber(
     name: &str,
     env_filter: &str,
     otlp_endpoint: Option<String>,
+    otlp_metrics: bool,
 ) -> anyhow::Result<()> {
     global::set_text_map_propagator(TraceContextPropagator::new());
 
+    if let (Some(otlp_endpoint), true) = (&otlp_endpoint, otlp_metrics) {
+        let provider = init_meter_provider(name, otlp_endpoint)?;
+        let _ = METER_PROVIDER.set(provider);
+    }
+
     let env_filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| EnvFilter::new(env_filter))
         .add_directive("otel::tracing=trace".parse()?)
@@ -0,0 +1,231 @@
+//! Extract structured tool calls from raw model output.
+//!
+//! Models that support function calling usually wrap every call into a pair of marker tags, e.g.
+//! `<tool_call>{"name": "get_weather", "arguments": {"city": "Paris"}}</tool_call>`. The parser
+//! splits the generated text into plain content and tool calls, and works incrementally so it can
+//! be fed with streamed deltas.
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::utils::partial_suffix_len;
+
+#[derive(Clone, Debug)]
+pub struct ToolCallFormat {
+    start_tag: String,
+    end_tag: String,
+}
+
+impl ToolCallFormat {
+    pub fn new(start_tag: &str, end_tag: &str) -> anyhow::Result<Self> {
+        if start_tag.is_empty() || end_tag.is_empty() {
+            anyhow::bail!("tool call tags must not be empty");
+        }
+        Ok(Self {
+            start_tag: start_tag.to_string(),
+            end_tag: end_tag.to_string(),
+        })
+    }
+}
+
+impl Default for ToolCallFormat {
+    fn default() -> Self {
+        Self {
+            start_tag: "<tool_call>".to_string(),
+            end_tag: "</tool_call>".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ParsedOutput {
+    /// Plain text that should be sent to the client as message content.
+    Content(String),
+    /// A complete tool call.
+    ToolCall(ParsedToolCall),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ParsedToolCall {
+    pub id: String,
+    pub name: String,
+    /// The arguments of the call, always encoded as a JSON string like OpenAI does.
+    pub arguments: String,
+}
+
+#[derive(Deserialize)]
+struct RawToolCall {
+    name: String,
+    #[serde(alias = "parameters", default)]
+    arguments: serde_json::Value,
+}
+
+pub(crate) struct ToolCallParser {
+    format: ToolCallFormat,
+    buffer: String,
+    in_tool_call: bool,
+}
+
+impl ToolCallParser {
+    pub fn new(format: ToolCallFormat) -> Self {
+        Self {
+            format,
+            buffer: String::new(),
+            in_tool_call: false,
+        }
+    }
+
+    /// Feed the next piece of generated text to the parser.
+    pub fn push(&mut self, text: &str) -> Vec<ParsedOutput> {
+        self.buffer.push_str(text);
+
+        let mut outputs = Vec::new();
+        loop {
+            if self.in_tool_call {
+                let Some(end) = self.buffer.find(&self.format.end_tag) else {
+                    break;
+                };
+                let body: String = self.buffer.drain(..end).collect();
+                self.buffer.drain(..self.format.end_tag.len());
+                self.in_tool_call = false;
+                let text = format!("{}{}{}", self.format.start_tag, body, self.format.end_tag);
+                outputs.push(
+                    self.parse_tool_call(body)
+                        .unwrap_or(ParsedOutput::Content(text)),
+                );
+            } else if let Some(start) = self.buffer.find(&self.format.start_tag) {
+                let content: String = self.buffer.drain(..start).collect();
+                self.buffer.drain(..self.format.start_tag.len());
+                self.in_tool_call = true;
+                push_content(&mut outputs, content);
+            } else {
+                // Hold back anything that may turn out to be the beginning of a start tag.
+                let keep = partial_suffix_len(&self.buffer, &self.format.start_tag);
+                let content: String = self.buffer.drain(..self.buffer.len() - keep).collect();
+                push_content(&mut outputs, content);
+                break;
+            }
+        }
+        outputs
+    }
+
+    /// Flush everything still buffered once generation is over.
+    pub fn finish(&mut self) -> Vec<ParsedOutput> {
+        let rest = std::mem::take(&mut self.buffer);
+        let mut outputs = Vec::new();
+        if self.in_tool_call {
+            // The end tag is frequently swallowed by the stop token, so try to parse the
+            // unterminated body before returning it as it was generated.
+            self.in_tool_call = false;
+            let text = format!("{}{}", self.format.start_tag, rest);
+            outputs.push(
+                self.parse_tool_call(rest)
+                    .unwrap_or(ParsedOutput::Content(text)),
+            );
+        } else {
+            push_content(&mut outputs, rest);
+        }
+        outputs
+    }
+
+    /// Parse the body of a tool call, `None` when it is not a valid call.
+    fn parse_tool_call(&self, body: String) -> Option<ParsedOutput> {
+        match serde_json::from_str::<RawToolCall>(body.trim()) {
+            Ok(call) => Some(ParsedOutput::ToolCall(ParsedToolCall {
+                id: format!("call_{}", Uuid::new_v4().simple()),
+                name: call.name,
+                arguments: match call.arguments {
+                    serde_json::Value::String(arguments) => arguments,
+                    serde_json::Value::Null => "{}".to_string(),
+                    arguments => arguments.to_string(),
+                },
+            })),
+            Err(e) => {
+                tracing::warn!("failed to parse tool call {:?}: {}", body, e);
+                None
+            }
+        }
+    }
+}
+
+fn push_content(outputs: &mut Vec<ParsedOutput>, content: String) {
+    if !content.is_empty() {
+        outputs.push(ParsedOutput::Content(content));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_all(chunks: &[&str]) -> Vec<ParsedOutput> {
+        let mut parser = ToolCallParser::new(ToolCallFormat::default());
+        let mut outputs: Vec<_> = chunks.iter().flat_map(|c| parser.push(c)).collect();
+        outputs.extend(parser.finish());
+        outputs
+    }
+
+    fn tool_call(output: &ParsedOutput) -> (&str, &str) {
+        match output {
+            ParsedOutput::ToolCall(call) => (call.name.as_str(), call.arguments.as_str()),
+            other => panic!("expected tool call, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_plain_content() {
+        let outputs = parse_all(&["Hello", " world"]);
+        assert_eq!(
+            vec![
+                ParsedOutput::Content("Hello".into()),
+                ParsedOutput::Content(" world".into())
+            ],
+            outputs
+        );
+    }
+
+    #[test]
+    pub fn test_tool_call_split_across_chunks() {
+        let outputs = parse_all(&[
+            "Let me check.<tool",
+            "_call>{\"name\": \"get_weather\", ",
+            "\"arguments\": {\"city\": \"Paris\"}}</tool_",
+            "call>",
+        ]);
+        assert_eq!(2, outputs.len());
+        assert_eq!(ParsedOutput::Content("Let me check.".into()), outputs[0]);
+        assert_eq!(
+            ("get_weather", "{\"city\":\"Paris\"}"),
+            tool_call(&outputs[1])
+        );
+    }
+
+    #[test]
+    pub fn test_multiple_and_unterminated_tool_calls() {
+        let outputs = parse_all(&[
+            "<tool_call>{\"name\": \"a\", \"parameters\": {}}</tool_call>\n",
+            "<tool_call>{\"name\": \"b\", \"arguments\": \"{\\\"x\\\": 1}\"}",
+        ]);
+        assert_eq!(3, outputs.len());
+        assert_eq!(("a", "{}"), tool_call(&outputs[0]));
+        assert_eq!(ParsedOutput::Content("\n".into()), outputs[1]);
+        assert_eq!(("b", "{\"x\": 1}"), tool_call(&outputs[2]));
+    }
+
+    #[test]
+    pub fn test_invalid_tool_call_is_kept_as_content() {
+        let outputs = parse_all(&["<tool_call>not json</tool_call>"]);
+        assert_eq!(
+            vec![ParsedOutput::Content(
+                "<tool_call>not json</tool_call>".into()
+            )],
+            outputs
+        );
+
+        // The end tag the model did not generate is not made up either.
+        let outputs = parse_all(&["<tool_call>not json"]);
+        assert_eq!(
+            vec![ParsedOutput::Content("<tool_call>not json".into())],
+            outputs
+        );
+    }
+}
@@ -1,4 +1,7 @@
 tonic::include_proto!("inference");
 
+pub(crate) mod pool;
 pub(crate) mod request;
+pub(crate) mod response;
+pub(crate) mod streams;
 pub(crate) mod telemetry;
@@ -0,0 +1,320 @@
+//! Load balancing over the replicas of a Triton deployment.
+//!
+//! Every request is sent to one endpoint of the pool, picked according to the configured
+//! [`LoadBalancing`] strategy. An endpoint that answers `Unavailable` is ejected from the pool for
+//! a while, requests only go to ejected endpoints when no other endpoint is left.
+//!
+//! Connections are established lazily, so that the gateway can start before Triton. A background
+//! task polls `ServerReady` on every endpoint, with an increasing delay while it is down, and
+//! endpoints that are not ready yet are avoided as well.
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::task::JoinSet;
+use tonic::transport::{Channel, Endpoint as ChannelEndpoint};
+
+use super::grpc_inference_service_client::GrpcInferenceServiceClient;
+use super::ServerReadyRequest;
+use crate::config::LoadBalancing;
+
+/// Delay between two readiness probes of a ready endpoint.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+/// Maximum time to wait for the answer of a readiness probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// First and maximum delay between two probes of an endpoint that is not ready.
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+struct Endpoint {
+    url: String,
+    client: GrpcInferenceServiceClient<Channel>,
+    /// Number of inferences currently streaming from this endpoint.
+    outstanding: AtomicUsize,
+    ejected_until: Mutex<Option<Instant>>,
+    /// Whether the last readiness probe succeeded.
+    ready: AtomicBool,
+}
+
+impl Endpoint {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| until > now)
+    }
+
+    /// Preference of the endpoint for new requests, lower is better.
+    fn rank(&self, now: Instant) -> u8 {
+        if self.is_ejected(now) {
+            2
+        } else if !self.ready.load(Ordering::Relaxed) {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Ask the endpoint whether it is ready, and remember the answer.
+    async fn probe(&self, timeout: Duration) -> anyhow::Result<bool> {
+        let mut client = self.client.clone();
+        let response = tokio::time::timeout(timeout, client.server_ready(ServerReadyRequest {}))
+            .await
+            .context("readiness probe timed out")
+            .and_then(|response| Ok(response?.into_inner().ready));
+        let ready = match &response {
+            Ok(ready) => *ready,
+            Err(e) => {
+                tracing::debug!(
+                    "readiness probe of triton endpoint {} failed: {:?}",
+                    self.url,
+                    e
+                );
+                false
+            }
+        };
+        if self.ready.swap(ready, Ordering::Relaxed) != ready {
+            if ready {
+                tracing::info!("triton endpoint {} is ready", self.url);
+            } else {
+                tracing::warn!("triton endpoint {} is not ready", self.url);
+            }
+        }
+        response
+    }
+
+    /// Probe the readiness of the endpoint forever.
+    async fn watch(&self) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let ready = self.probe(PROBE_TIMEOUT).await.unwrap_or(false);
+            if ready {
+                backoff = MIN_BACKOFF;
+                tokio::time::sleep(PROBE_INTERVAL).await;
+            } else {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EndpointPool {
+    endpoints: Arc<Vec<Endpoint>>,
+    load_balancing: LoadBalancing,
+    ejection_duration: Duration,
+    next: Arc<AtomicUsize>,
+}
+
+impl EndpointPool {
+    /// Create the pool without connecting to the endpoints yet, see [`EndpointPool::watch`].
+    pub fn new(
+        urls: &[String],
+        load_balancing: LoadBalancing,
+        ejection_duration: Duration,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(!urls.is_empty(), "no triton endpoint given");
+        let endpoints = urls
+            .iter()
+            .map(|url| {
+                let channel = ChannelEndpoint::from_shared(url.clone())
+                    .with_context(|| format!("invalid triton endpoint {}", url))?
+                    .connect_lazy();
+                Ok(Endpoint {
+                    url: url.clone(),
+                    client: GrpcInferenceServiceClient::new(channel),
+                    outstanding: AtomicUsize::new(0),
+                    ejected_until: Mutex::new(None),
+                    ready: AtomicBool::new(false),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            endpoints: Arc::new(endpoints),
+            load_balancing,
+            ejection_duration,
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Start polling the readiness of every endpoint in the background.
+    pub fn watch(&self) {
+        for index in 0..self.endpoints.len() {
+            let endpoints = self.endpoints.clone();
+            tokio::spawn(async move { endpoints[index].watch().await });
+        }
+    }
+
+    /// Probe the readiness of every endpoint right away, waiting at most `timeout`.
+    ///
+    /// Returns the url of every endpoint along with whether it is ready.
+    pub async fn probe(&self, timeout: Duration) -> Vec<(String, anyhow::Result<bool>)> {
+        let mut probes = JoinSet::new();
+        for index in 0..self.endpoints.len() {
+            let endpoints = self.endpoints.clone();
+            probes.spawn(async move { (index, endpoints[index].probe(timeout).await) });
+        }
+
+        let mut results: Vec<_> = (0..self.endpoints.len()).map(|_| None).collect();
+        while let Some(probe) = probes.join_next().await {
+            if let Ok((index, ready)) = probe {
+                results[index] = Some(ready);
+            }
+        }
+        self.endpoints
+            .iter()
+            .zip(results)
+            .map(|(endpoint, ready)| {
+                let ready = ready.unwrap_or_else(|| Err(anyhow::anyhow!("readiness probe failed")));
+                (endpoint.url.clone(), ready)
+            })
+            .collect()
+    }
+
+    /// Client of the endpoint that should serve the next request.
+    pub fn client(&self) -> GrpcInferenceServiceClient<Channel> {
+        let lease = self.lease(&[]).expect("the pool is never empty");
+        lease.client()
+    }
+
+    /// Reserve the endpoint that should serve the next request, skipping the endpoints at the
+    /// `excluded` indices. Returns `None` when every endpoint is excluded.
+    pub fn lease(&self, excluded: &[usize]) -> Option<Lease> {
+        let now = Instant::now();
+        let candidates: Vec<_> = (0..self.endpoints.len())
+            .filter(|index| !excluded.contains(index))
+            .collect();
+        // Endpoints that are ejected or not ready may have recovered in the meantime, trying them
+        // is better than failing right away.
+        let best = candidates
+            .iter()
+            .map(|&index| self.endpoints[index].rank(now))
+            .min()?;
+        let candidates: Vec<_> = candidates
+            .into_iter()
+            .filter(|&index| self.endpoints[index].rank(now) == best)
+            .collect();
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        let rotated = candidates[start..].iter().chain(&candidates[..start]);
+        let index = match self.load_balancing {
+            LoadBalancing::RoundRobin => candidates[start],
+            LoadBalancing::LeastOutstanding => *rotated
+                .min_by_key(|&&index| self.endpoints[index].outstanding.load(Ordering::Relaxed))
+                .unwrap(),
+        };
+
+        self.endpoints[index]
+            .outstanding
+            .fetch_add(1, Ordering::Relaxed);
+        Some(Lease {
+            pool: self.clone(),
+            index,
+        })
+    }
+
+    /// Stop sending requests to the endpoint at `index` for the ejection duration.
+    pub fn eject(&self, index: usize) {
+        let endpoint = &self.endpoints[index];
+        tracing::warn!(
+            "ejecting unavailable triton endpoint {} for {:?}",
+            endpoint.url,
+            self.ejection_duration
+        );
+        *endpoint.ejected_until.lock().unwrap() = Some(Instant::now() + self.ejection_duration);
+    }
+}
+
+/// An endpoint reserved for a request, counted as outstanding until dropped.
+pub struct Lease {
+    pool: EndpointPool,
+    index: usize,
+}
+
+impl Lease {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn url(&self) -> &str {
+        &self.pool.endpoints[self.index].url
+    }
+
+    pub fn client(&self) -> GrpcInferenceServiceClient<Channel> {
+        self.pool.endpoints[self.index].client.clone()
+    }
+
+    /// Eject the endpoint from the pool, see [`EndpointPool::eject`].
+    pub fn eject(&self) {
+        self.pool.eject(self.index)
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pool.endpoints[self.index]
+            .outstanding
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pool(size: usize, load_balancing: LoadBalancing) -> EndpointPool {
+        let urls: Vec<_> = (0..size)
+            .map(|i| format!("http://triton-{}:8001", i))
+            .collect();
+        let pool = EndpointPool::new(&urls, load_balancing, Duration::from_secs(60)).unwrap();
+        for endpoint in pool.endpoints.iter() {
+            endpoint.ready.store(true, Ordering::Relaxed);
+        }
+        pool
+    }
+
+    #[tokio::test]
+    pub async fn test_round_robin() {
+        let pool = pool(3, LoadBalancing::RoundRobin);
+
+        let picked: Vec<_> = (0..6).map(|_| pool.lease(&[]).unwrap().index()).collect();
+        assert_eq!(vec![0, 1, 2, 0, 1, 2], picked);
+        assert_eq!(1, pool.lease(&[0, 2]).unwrap().index());
+        assert!(pool.lease(&[0, 1, 2]).is_none());
+    }
+
+    #[tokio::test]
+    pub async fn test_least_outstanding() {
+        let pool = pool(3, LoadBalancing::LeastOutstanding);
+
+        let first = pool.lease(&[]).unwrap();
+        let second = pool.lease(&[]).unwrap();
+        assert_ne!(first.index(), second.index());
+        let third = pool.lease(&[]).unwrap();
+        drop(second);
+        // Only the endpoint of the dropped lease is idle.
+        let index = pool.lease(&[]).unwrap().index();
+        assert_ne!(first.index(), index);
+        assert_ne!(third.index(), index);
+    }
+
+    #[tokio::test]
+    pub async fn test_ejection() {
+        let pool = pool(2, LoadBalancing::RoundRobin);
+
+        pool.eject(0);
+        assert!((0..4).all(|_| pool.lease(&[]).unwrap().index() == 1));
+        // The ejected endpoint is still used as a last resort.
+        assert_eq!(0, pool.lease(&[1]).unwrap().index());
+    }
+
+    #[tokio::test]
+    pub async fn test_ready_endpoints_are_preferred() {
+        let pool = pool(2, LoadBalancing::RoundRobin);
+
+        pool.endpoints[1].ready.store(false, Ordering::Relaxed);
+        assert!((0..4).all(|_| pool.lease(&[]).unwrap().index() == 0));
+    }
+}
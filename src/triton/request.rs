@@ -27,7 +27,7 @@ impl Builder {
         })
     }
 
-    fn model_version<S>(self, model_version: S) -> Self
+    pub(crate) fn model_version<S>(self, model_version: S) -> Self
     where
         S: Into<String>,
     {
@@ -0,0 +1,45 @@
+use anyhow::Context;
+
+use super::ModelInferResponse;
+use crate::utils::deserialize_bytes_tensor;
+
+/// Look up the raw contents of an output tensor by name.
+///
+/// Triton returns `raw_output_contents` in the same order as `outputs`, which does not necessarily
+/// match the order the outputs were requested in.
+pub(crate) fn raw_output<'a>(response: &'a ModelInferResponse, name: &str) -> Option<&'a [u8]> {
+    response
+        .outputs
+        .iter()
+        .position(|output| output.name == name)
+        .and_then(|index| response.raw_output_contents.get(index))
+        .map(Vec::as_slice)
+}
+
+/// Read a BYTES output tensor and concatenate its elements.
+pub(crate) fn string_output(
+    response: &ModelInferResponse,
+    name: &str,
+) -> anyhow::Result<Option<String>> {
+    raw_output(response, name)
+        .map(|raw| {
+            deserialize_bytes_tensor(raw.to_vec())
+                .map(|strs| strs.into_iter().collect())
+                .with_context(|| format!("failed to deserialize triton output {}", name))
+        })
+        .transpose()
+}
+
+/// Read the first element of a FP32 output tensor.
+pub(crate) fn f32_output(response: &ModelInferResponse, name: &str) -> Option<f32> {
+    raw_output(response, name)
+        .and_then(|raw| raw.get(..4))
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Read the first element of a UINT32 output tensor.
+pub(crate) fn u32_output(response: &ModelInferResponse, name: &str) -> Option<u32> {
+    raw_output(response, name)
+        .and_then(|raw| raw.get(..4))
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
@@ -0,0 +1,303 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use async_stream::stream;
+use axum::http::HeaderMap;
+use tonic::codegen::tokio_stream::{Stream, StreamExt, StreamMap};
+use tonic::{Code, Status};
+use tracing::{Instrument, Span};
+
+use super::pool::EndpointPool;
+use super::telemetry::propagate_context;
+use super::{ModelInferRequest, ModelStreamInferResponse};
+use crate::metrics::metrics;
+
+/// Responses of a single streaming inference, terminated by `None` once Triton closed the stream.
+pub(crate) type ResponseStream =
+    Pin<Box<dyn Stream<Item = Option<Result<ModelStreamInferResponse, Status>>> + Send>>;
+
+/// Start a streaming inference on an endpoint of the pool.
+///
+/// The request is retried on another endpoint when the chosen one is unavailable, as long as it
+/// did not send any response yet.
+pub(crate) async fn model_stream_infer(
+    pool: &EndpointPool,
+    headers: &HeaderMap,
+    request: ModelInferRequest,
+    triton_model_label: &str,
+) -> anyhow::Result<ResponseStream> {
+    let triton_model_label = triton_model_label.to_string();
+    let mut tried = Vec::new();
+    loop {
+        let lease = match pool.lease(&tried) {
+            Some(lease) => lease,
+            None => anyhow::bail!(Status::unavailable("no triton endpoint is available")),
+        };
+        tried.push(lease.index());
+
+        let attempt = request.clone();
+        let mut grpc_request = tonic::Request::new(stream! { yield attempt });
+        propagate_context(&mut grpc_request, headers);
+
+        let result = lease.client().model_stream_infer(grpc_request).await;
+        if let Err(status) = &result {
+            count_error(&triton_model_label, status);
+        }
+        let mut responses = match result {
+            Ok(response) => response.into_inner(),
+            Err(status) if status.code() == Code::Unavailable => {
+                tracing::warn!("triton endpoint {} is unavailable: {}", lease.url(), status);
+                lease.eject();
+                continue;
+            }
+            Err(status) => {
+                return Err(status).context("failed to call triton grpc method model_stream_infer")
+            }
+        };
+        let first = responses.next().await;
+        if let Some(Err(status)) = &first {
+            if status.code() == Code::Unavailable {
+                count_error(&triton_model_label, status);
+                tracing::warn!("triton endpoint {} is unavailable: {}", lease.url(), status);
+                lease.eject();
+                continue;
+            }
+        }
+
+        return Ok(Box::pin(stream! {
+            // The endpoint counts as busy as long as the responses are consumed.
+            let lease = lease;
+            let mut cancellation = Cancellation {
+                model: request.model_name.clone(),
+                url: lease.url().to_string(),
+                completed: false,
+            };
+            let mut next = first;
+            while let Some(response) = next {
+                match &response {
+                    Ok(response) if !response.error_message.is_empty() => {
+                        metrics().triton_error(&triton_model_label, "inference");
+                    }
+                    Ok(_) => {}
+                    Err(status) => {
+                        count_error(&triton_model_label, status);
+                        if status.code() == Code::Unavailable {
+                            lease.eject();
+                        }
+                    }
+                }
+                yield Some(response);
+                next = responses.next().await;
+            }
+            cancellation.completed = true;
+            yield None;
+        }));
+    }
+}
+
+/// Reports the inferences dropped before Triton completed them, typically because the client
+/// disconnected or a timeout elapsed.
+///
+/// Dropping the responses resets the gRPC stream, Triton then cancels the request, which stops
+/// the generation of the vLLM engine.
+struct Cancellation {
+    model: String,
+    url: String,
+    completed: bool,
+}
+
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        if !self.completed {
+            tracing::info!(
+                "cancelled inference of model {} on triton endpoint {}",
+                self.model,
+                self.url
+            );
+        }
+    }
+}
+
+fn count_error(triton_model_label: &str, status: &Status) {
+    metrics().triton_error(triton_model_label, &format!("{:?}", status.code()));
+}
+
+/// Start one streaming inference per request and merge their responses as they arrive.
+///
+/// The inferences are started concurrently, as the returned stream is polled. Every item is keyed
+/// by the index of the request it belongs to, so that callers can tell the generations apart. The
+/// responses of an inference are passed on as soon as it starts, whatever the others do, and an
+/// inference that fails to start or misses the first token deadline ends with its error.
+pub(crate) fn model_stream_infer_all(
+    pool: &EndpointPool,
+    headers: &HeaderMap,
+    requests: Vec<ModelInferRequest>,
+    triton_model_label: &str,
+    deadline: Deadline,
+) -> StreamMap<usize, ResponseStream> {
+    let mut streams = StreamMap::with_capacity(requests.len());
+    for (index, request) in requests.into_iter().enumerate() {
+        let pool = pool.clone();
+        let headers = headers.clone();
+        let triton_model_label = triton_model_label.to_string();
+        let span = Span::current();
+        let stream: ResponseStream = Box::pin(stream! {
+            let started = model_stream_infer(&pool, &headers, request, &triton_model_label);
+            match deadline.first_token(started).instrument(span).await {
+                Ok(mut responses) => {
+                    while let Some(response) = responses.next().await {
+                        yield response;
+                    }
+                }
+                Err(e) => {
+                    let status = match e.downcast::<Status>() {
+                        Ok(status) => status,
+                        Err(e) => Status::internal(format!("{:#}", e)),
+                    };
+                    yield Some(Err(status));
+                }
+            }
+        });
+        streams.insert(index, stream);
+    }
+    streams
+}
+
+/// Limits on the time taken by an inference, none when not set.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Timeouts {
+    /// From the start of the request to the last response.
+    pub total: Option<Duration>,
+    /// From the start of the request to the first response.
+    pub first_token: Option<Duration>,
+}
+
+impl Timeouts {
+    /// The timeouts of an inference that is not streamed. Its only response comes at the end of
+    /// the generation, so that it is only bounded by the total timeout.
+    pub fn unstreamed(self) -> Self {
+        Self {
+            first_token: None,
+            ..self
+        }
+    }
+}
+
+/// Enforces the [`Timeouts`] of an inference while its responses are awaited.
+#[derive(Clone, Copy)]
+pub(crate) struct Deadline {
+    start: Instant,
+    timeouts: Timeouts,
+}
+
+impl Deadline {
+    pub fn new(timeouts: Timeouts) -> Self {
+        Self {
+            start: Instant::now(),
+            timeouts,
+        }
+    }
+
+    /// Run `future`, failing with `DeadlineExceeded` when the total timeout elapses first.
+    pub async fn run<T>(
+        &self,
+        future: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let timeout = self.timeouts.total;
+        let message = |timeout| format!("the request timed out after {:?}", timeout);
+        self.run_until(timeout, message, future).await
+    }
+
+    /// Run `future`, which awaits the first response of an inference, failing with
+    /// `DeadlineExceeded` when the first token timeout elapses first.
+    pub async fn first_token<T>(
+        &self,
+        future: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let timeout = self.timeouts.first_token;
+        let message = |timeout| format!("no token was generated within {:?}", timeout);
+        self.run_until(timeout, message, future).await
+    }
+
+    async fn run_until<T>(
+        &self,
+        timeout: Option<Duration>,
+        message: impl FnOnce(Duration) -> String,
+        future: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let Some(timeout) = timeout else {
+            return future.await;
+        };
+        match tokio::time::timeout_at((self.start + timeout).into(), future).await {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!(Status::deadline_exceeded(message(timeout))),
+        }
+    }
+
+    /// Wait for the next response of `stream`.
+    pub async fn next<S: Stream + Unpin>(&self, stream: &mut S) -> anyhow::Result<Option<S::Item>> {
+        self.run(async { Ok(stream.next().await) }).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tonic::codegen::tokio_stream;
+
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_first_token_timeout() {
+        let deadline = Deadline::new(Timeouts {
+            total: None,
+            first_token: Some(Duration::from_millis(10)),
+        });
+
+        let error = deadline
+            .first_token(std::future::pending::<anyhow::Result<()>>())
+            .await
+            .unwrap_err();
+        let status = error.downcast_ref::<Status>().unwrap();
+        assert_eq!(Code::DeadlineExceeded, status.code());
+
+        // Once an inference started, only the total timeout applies.
+        let mut stream = tokio_stream::pending::<()>();
+        let next =
+            tokio::time::timeout(Duration::from_millis(50), deadline.next(&mut stream)).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_unstreamed_timeout() {
+        let timeouts = Timeouts {
+            total: Some(Duration::from_millis(50)),
+            first_token: Some(Duration::from_millis(10)),
+        };
+        let deadline = Deadline::new(timeouts.unstreamed());
+
+        // The single response of the inference may come after the first token timeout.
+        let response = deadline.first_token(async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(1)
+        });
+        assert_eq!(1, response.await.unwrap());
+    }
+
+    #[tokio::test]
+    pub async fn test_total_timeout() {
+        let deadline = Deadline::new(Timeouts {
+            total: Some(Duration::from_millis(10)),
+            first_token: Some(Duration::from_secs(60)),
+        });
+
+        let mut stream = tokio_stream::iter([1]).chain(tokio_stream::pending());
+        assert_eq!(Some(1), deadline.next(&mut stream).await.unwrap());
+        let error = deadline.next(&mut stream).await.unwrap_err();
+        assert_eq!(
+            "the request timed out after 10ms",
+            error.downcast_ref::<Status>().unwrap().message()
+        );
+    }
+}
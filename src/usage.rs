@@ -0,0 +1,130 @@
+//! Token accounting for the usage statistics reported back to clients.
+//!
+//! The vLLM backend reports token counts through its optional `num_input_tokens` and
+//! `num_output_tokens` outputs. When those are not available, the text is tokenized locally with
+//! the configured tokenizer instead.
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokenizers::Tokenizer;
+
+use crate::finish_reason::FINISH_REASON;
+use crate::triton::request::{Builder, InferTensorData};
+use crate::triton::response::u32_output;
+use crate::triton::ModelInferResponse;
+
+pub(crate) const NUM_INPUT_TOKENS: &str = "num_input_tokens";
+pub(crate) const NUM_OUTPUT_TOKENS: &str = "num_output_tokens";
+
+/// Ask the vLLM backend for the token counts and the finish reason along with the generated text.
+pub(crate) fn additional_outputs(builder: Builder) -> Builder {
+    builder
+        .input(
+            "return_num_input_tokens",
+            [1],
+            InferTensorData::Bool(vec![true]),
+        )
+        .input(
+            "return_num_output_tokens",
+            [1],
+            InferTensorData::Bool(vec![true]),
+        )
+        .input(
+            "return_finish_reason",
+            [1],
+            InferTensorData::Bool(vec![true]),
+        )
+        .output(NUM_INPUT_TOKENS)
+        .output(NUM_OUTPUT_TOKENS)
+        .output(FINISH_REASON)
+}
+
+#[derive(Clone, Default)]
+pub struct TokenCounter {
+    tokenizer: Option<Arc<Tokenizer>>,
+}
+
+impl TokenCounter {
+    pub fn new(tokenizer_file: &Option<String>) -> anyhow::Result<Self> {
+        let tokenizer = match tokenizer_file {
+            None => None,
+            Some(file) => Some(Arc::new(
+                Tokenizer::from_file(file)
+                    .map_err(|e| anyhow::anyhow!(e))
+                    .with_context(|| format!("failed to load tokenizer file {}", file))?,
+            )),
+        };
+        Ok(Self { tokenizer })
+    }
+
+    /// Decode token ids back to text, if a tokenizer is configured.
+    pub fn decode(&self, tokens: &[u32]) -> anyhow::Result<Option<String>> {
+        let Some(tokenizer) = self.tokenizer.as_ref() else {
+            return Ok(None);
+        };
+        let text = tokenizer
+            .decode(tokens, false)
+            .map_err(|e| anyhow::anyhow!(e))
+            .context("failed to decode token ids")?;
+        Ok(Some(text))
+    }
+
+    /// Count the tokens of `text`, if a tokenizer is configured.
+    fn count(&self, text: &str) -> Option<usize> {
+        let tokenizer = self.tokenizer.as_ref()?;
+        match tokenizer.encode(text, false) {
+            Ok(encoding) => Some(encoding.len()),
+            Err(e) => {
+                tracing::warn!("failed to tokenize text for usage accounting: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Accumulates the token counts of a generation across streamed responses.
+#[derive(Default, Debug)]
+pub(crate) struct UsageTracker {
+    prompt_tokens: Option<usize>,
+    completion_tokens: Option<usize>,
+}
+
+impl UsageTracker {
+    /// Tracker of a generation whose prompt is known to be `prompt_tokens` long, e.g. because it
+    /// was given as token ids.
+    pub fn with_prompt_tokens(prompt_tokens: usize) -> Self {
+        Self {
+            prompt_tokens: Some(prompt_tokens),
+            completion_tokens: None,
+        }
+    }
+
+    pub fn record(&mut self, response: &ModelInferResponse) {
+        if let Some(num_input_tokens) = u32_output(response, NUM_INPUT_TOKENS) {
+            self.prompt_tokens = Some(num_input_tokens as usize);
+        }
+        // In streaming mode every response only reports the tokens generated since the last one.
+        if let Some(num_output_tokens) = u32_output(response, NUM_OUTPUT_TOKENS) {
+            *self.completion_tokens.get_or_insert(0) += num_output_tokens as usize;
+        }
+    }
+
+    /// Number of generated tokens, if known.
+    pub fn completion_tokens(&self, counter: &TokenCounter, completion: &str) -> Option<usize> {
+        self.completion_tokens.or_else(|| counter.count(completion))
+    }
+
+    /// Return the `(prompt_tokens, completion_tokens)` of the generation, falling back to the
+    /// local tokenizer for anything the backend did not report.
+    pub fn finish(&self, counter: &TokenCounter, prompt: &str, completion: &str) -> (usize, usize) {
+        let prompt_tokens = self.prompt_tokens.or_else(|| counter.count(prompt));
+        let completion_tokens = self.completion_tokens(counter, completion);
+        if prompt_tokens.is_none() || completion_tokens.is_none() {
+            tracing::debug!("token counts not available, configure a tokenizer to report usage");
+        }
+        (
+            prompt_tokens.unwrap_or_default(),
+            completion_tokens.unwrap_or_default(),
+        )
+    }
+}
@@ -26,6 +26,13 @@ where
             Ok(vec![value.to_owned()])
         }
 
+        fn visit_unit<E>(self) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(vec![])
+        }
+
         fn visit_seq<S>(self, visitor: S) -> Result<Self::Value, S::Error>
         where
             S: de::SeqAccess<'de>,
@@ -50,3 +57,15 @@ pub(crate) fn deserialize_bytes_tensor(encoded_tensor: Vec<u8>) -> Result<Vec<St
     }
     Ok(strs)
 }
+
+/// Length of the longest suffix of `text` that is a proper prefix of `pattern`, i.e. how much of
+/// `text` has to be held back because it may be completed into `pattern` by the next chunk.
+pub(crate) fn partial_suffix_len(text: &str, pattern: &str) -> usize {
+    (1..pattern.len().min(text.len() + 1))
+        .rev()
+        .find(|&len| {
+            text.is_char_boundary(text.len() - len)
+                && pattern.as_bytes()[..len] == text.as_bytes()[text.len() - len..]
+        })
+        .unwrap_or(0)
+}